@@ -3,8 +3,12 @@
 #include "trigger.h"
 
 int trigger(void *request) {
+	const unsigned char *body = request_get_body_bytes(request);
+
 	printf("%s", request_get_method(request));
 	printf("%s", request_get_uri(request));
-	printf("%s", request_get_body(request));
+	if (body != NULL) {
+		fwrite(body, 1, request_get_body_len(request), stdout);
+	}
 	return 0;
 }
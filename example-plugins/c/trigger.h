@@ -1,4 +1,24 @@
+#include <stddef.h>
+
 char *request_get_method(const void *);
 char *request_get_uri(const void *);
-char *request_get_header(const void *, char *);
+char *request_get_path(const void *);
+char *request_get_query(const void *);
+char *request_get_path_param(const void *, const char *);
+char *request_get_query_param(const void *, const char *);
+size_t request_query_param_count(const void *);
+char *request_query_param_name_at(const void *, size_t);
+char *request_query_param_value_at(const void *, size_t);
+char *request_get_header(const void *, const char *);
+size_t request_header_count(const void *);
+char *request_header_name_at(const void *, size_t);
+char *request_header_value_at(const void *, size_t);
 char *request_get_body(const void *);
+size_t request_get_body_len(const void *);
+const unsigned char *request_get_body_bytes(const void *);
+char *request_get_client_subject(const void *);
+size_t request_get_client_san_count(const void *);
+char *request_get_client_san(const void *, size_t);
+int response_set_status(void *, int);
+int response_set_header(void *, const char *, const char *);
+int response_set_body(void *, const unsigned char *, size_t);
@@ -13,10 +13,10 @@ import (
 func trigger(request unsafe.Pointer) C.int {
   method := C.GoString(C.request_get_method(request))
   uri := C.GoString(C.request_get_uri(request))
-  body := C.GoString(C.request_get_body(request))
+  body := C.GoBytes(unsafe.Pointer(C.request_get_body_bytes(request)), C.int(C.request_get_body_len(request)))
   fmt.Println(method)
   fmt.Println(uri)
-  fmt.Println(body)
+  fmt.Println(string(body))
   return 0
 }
 
@@ -1,19 +1,72 @@
 extern crate libc;
 extern crate serde_json;
 
-use std::collections::HashMap;
 use std::error::Error;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::ptr;
+use std::slice;
 use std::str;
 
 use serde_json::{Map, Value};
 
+/// Version of the plugin ABI described by this crate. The host rejects
+/// plugins built against a version it does not support.
+pub const ABI_VERSION: u32 = 1;
+
+/// `ABI_VERSION` exported for the host to check, so every plugin linked
+/// against this crate carries the version it was built against.
+#[no_mangle]
+#[used]
+pub static miss_demeanor_abi_version: u32 = ABI_VERSION;
+
+/// Raw request body. A NUL byte is kept after the end of the data so text
+/// bodies can also be handed out as C strings.
+pub struct Body(Vec<u8>);
+
+impl Body {
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        bytes.push(0);
+        Body(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..self.0.len() - 1]
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The body as a C string, or `None` if it contains a NUL byte and would
+    /// be truncated.
+    pub fn as_c_str(&self) -> Option<&CStr> {
+        CStr::from_bytes_with_nul(&self.0).ok()
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::new(bytes)
+    }
+}
+
 pub struct CRequest {
     pub method: CString,
     pub uri: CString,
-    pub headers: HashMap<CString, CString>,
-    pub body: CString,
+    pub path: CString,
+    pub query: CString,
+    pub path_params: Vec<(CString, CString)>,
+    pub query_params: Vec<(CString, CString)>,
+    /// Header names and values in the order they were received; a header
+    /// sent several times appears once per value.
+    pub headers: Vec<(CString, CString)>,
+    pub body: Body,
+    pub client_subject: Option<CString>,
+    pub client_sans: Vec<CString>,
 }
 
 impl CRequest {
@@ -25,27 +78,88 @@ impl CRequest {
         self.uri.to_str()
     }
 
+    pub fn get_path(&self) -> Result<&str, str::Utf8Error> {
+        self.path.to_str()
+    }
+
+    pub fn get_query(&self) -> Result<&str, str::Utf8Error> {
+        self.query.to_str()
+    }
+
+    pub fn get_path_param(&self, name: &str) -> Option<&str> {
+        self.path_params
+            .iter()
+            .find(|(k, _)| k.as_bytes() == name.as_bytes())
+            .and_then(|(_, v)| v.to_str().ok())
+    }
+
+    pub fn get_query_param(&self, name: &str) -> Option<&str> {
+        self.query_params
+            .iter()
+            .find(|(k, _)| k.as_bytes() == name.as_bytes())
+            .and_then(|(_, v)| v.to_str().ok())
+    }
+
+    pub fn get_query_params(&self) -> Vec<(&str, &str)> {
+        self.query_params
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_str().ok()?, v.to_str().ok()?)))
+            .collect()
+    }
+
+    /// First value of a header, looked up case-insensitively.
     pub fn get_header(&self, key: &str) -> Option<&str> {
-        let key_cstring = match CString::new(key.as_bytes()) {
-            Ok(cs) => cs,
-            Err(_) => return None,
-        };
-        self.headers.get(&key_cstring).and_then(|v| v.to_str().ok())
+        self.get_header_values(key).into_iter().next()
     }
 
-    pub fn get_headers(&self) -> Result<String, Box<dyn Error>> {
+    /// Every value of a header in the order received, looked up
+    /// case-insensitively.
+    pub fn get_header_values(&self, key: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.as_bytes().eq_ignore_ascii_case(key.as_bytes()))
+            .filter_map(|(_, v)| v.to_str().ok())
+            .collect()
+    }
+
+    /// Headers as a map of lowercase names to values. Values of a header
+    /// sent several times are joined with `, `.
+    pub fn get_headers_map(&self) -> Result<Map<String, Value>, str::Utf8Error> {
         let mut map = Map::<String, Value>::new();
         for (key, value) in self.headers.iter() {
-            map.insert(
-                key.to_str()?.to_string(),
-                Value::from(value.to_str()?.to_string()),
-            );
+            let key = key.to_str()?.to_ascii_lowercase();
+            let value = value.to_str()?;
+            let joined = match map.get(&key).and_then(|v| v.as_str()) {
+                Some(prev) => format!("{}, {}", prev, value),
+                None => value.to_string(),
+            };
+            map.insert(key, Value::from(joined));
         }
-        Ok(serde_json::to_string(&map)?)
+        Ok(map)
+    }
+
+    /// Headers as a JSON object, as returned by `get_headers_map`.
+    pub fn get_headers(&self) -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::to_string(&self.get_headers_map()?)?)
     }
 
     pub fn get_body(&self) -> Result<&str, str::Utf8Error> {
-        self.body.to_str()
+        str::from_utf8(self.body.as_bytes())
+    }
+
+    pub fn get_body_bytes(&self) -> &[u8] {
+        self.body.as_bytes()
+    }
+
+    pub fn get_client_subject(&self) -> Option<&str> {
+        self.client_subject.as_ref().and_then(|s| s.to_str().ok())
+    }
+
+    pub fn get_client_sans(&self) -> Vec<&str> {
+        self.client_sans
+            .iter()
+            .filter_map(|s| s.to_str().ok())
+            .collect()
     }
 }
 
@@ -79,6 +193,127 @@ pub unsafe extern "C" fn request_get_uri(req: *const CRequest) -> *const libc::c
     request.uri.as_ptr()
 }
 
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_path(req: *const CRequest) -> *const libc::c_char {
+    let request = match req.as_ref() {
+        Some(r) => r,
+        None => {
+            return ptr::null();
+        }
+    };
+    request.path.as_ptr()
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_query(req: *const CRequest) -> *const libc::c_char {
+    let request = match req.as_ref() {
+        Some(r) => r,
+        None => {
+            return ptr::null();
+        }
+    };
+    request.query.as_ptr()
+}
+
+unsafe fn find_param(
+    params: &[(CString, CString)],
+    name: *const libc::c_char,
+) -> *const libc::c_char {
+    if name.is_null() {
+        return ptr::null();
+    }
+    let name = CStr::from_ptr(name);
+    match params.iter().find(|(k, _)| k.as_c_str() == name) {
+        Some((_, v)) => v.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor and
+/// a null terminated string for `name` only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_path_param(
+    req: *const CRequest,
+    name: *const libc::c_char,
+) -> *const libc::c_char {
+    match req.as_ref() {
+        Some(r) => find_param(&r.path_params, name),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor and
+/// a null terminated string for `name` only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_query_param(
+    req: *const CRequest,
+    name: *const libc::c_char,
+) -> *const libc::c_char {
+    match req.as_ref() {
+        Some(r) => find_param(&r.query_params, name),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_query_param_count(req: *const CRequest) -> libc::size_t {
+    match req.as_ref() {
+        Some(r) => r.query_params.len(),
+        None => 0,
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_query_param_name_at(
+    req: *const CRequest,
+    index: libc::size_t,
+) -> *const libc::c_char {
+    match req.as_ref().and_then(|r| r.query_params.get(index)) {
+        Some((k, _)) => k.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_query_param_value_at(
+    req: *const CRequest,
+    index: libc::size_t,
+) -> *const libc::c_char {
+    match req.as_ref().and_then(|r| r.query_params.get(index)) {
+        Some((_, v)) => v.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Returns the first value of the header named `key`, compared
+/// case-insensitively. `key` is only borrowed.
+///
 /// # Safety
 ///
 /// Safe when used with a request generated by Miss Demeanor and
@@ -87,7 +322,7 @@ pub unsafe extern "C" fn request_get_uri(req: *const CRequest) -> *const libc::c
 #[no_mangle]
 pub unsafe extern "C" fn request_get_header(
     req: *const CRequest,
-    key: *mut libc::c_char,
+    key: *const libc::c_char,
 ) -> *const libc::c_char {
     let request = match req.as_ref() {
         Some(r) => r,
@@ -95,14 +330,81 @@ pub unsafe extern "C" fn request_get_header(
             return ptr::null();
         }
     };
-    let cstring = CString::from_raw(key);
-    let header = match request.headers.get(&cstring) {
-        Some(h) => h,
+    if key.is_null() {
+        return ptr::null();
+    }
+    let key = CStr::from_ptr(key);
+    match request
+        .headers
+        .iter()
+        .find(|(k, _)| k.as_bytes().eq_ignore_ascii_case(key.to_bytes()))
+    {
+        Some((_, v)) => v.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_header_count(req: *const CRequest) -> libc::size_t {
+    match req.as_ref() {
+        Some(r) => r.headers.len(),
+        None => 0,
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_header_name_at(
+    req: *const CRequest,
+    index: libc::size_t,
+) -> *const libc::c_char {
+    match req.as_ref().and_then(|r| r.headers.get(index)) {
+        Some((k, _)) => k.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_header_value_at(
+    req: *const CRequest,
+    index: libc::size_t,
+) -> *const libc::c_char {
+    match req.as_ref().and_then(|r| r.headers.get(index)) {
+        Some((_, v)) => v.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Returns null if the body contains a NUL byte; use
+/// `request_get_body_bytes` for binary bodies.
+///
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_body(req: *const CRequest) -> *const libc::c_char {
+    let request = match req.as_ref() {
+        Some(r) => r,
         None => {
             return ptr::null();
         }
     };
-    header.as_ptr()
+    match request.body.as_c_str() {
+        Some(s) => s.as_ptr(),
+        None => ptr::null(),
+    }
 }
 
 /// # Safety
@@ -110,12 +412,195 @@ pub unsafe extern "C" fn request_get_header(
 /// Safe when used with a request generated by Miss Demeanor only
 #[allow(dead_code)]
 #[no_mangle]
-pub unsafe extern "C" fn request_get_body(req: *const CRequest) -> *const libc::c_char {
+pub unsafe extern "C" fn request_get_body_len(req: *const CRequest) -> libc::size_t {
+    match req.as_ref() {
+        Some(r) => r.body.len(),
+        None => 0,
+    }
+}
+
+/// Returns a pointer to `request_get_body_len` bytes of body data.
+///
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_body_bytes(req: *const CRequest) -> *const u8 {
+    match req.as_ref() {
+        Some(r) => r.body.as_bytes().as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_client_subject(req: *const CRequest) -> *const libc::c_char {
+    let request = match req.as_ref() {
+        Some(r) => r,
+        None => {
+            return ptr::null();
+        }
+    };
+    match request.client_subject {
+        Some(ref s) => s.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_client_san_count(req: *const CRequest) -> libc::size_t {
+    match req.as_ref() {
+        Some(r) => r.client_sans.len(),
+        None => 0,
+    }
+}
+
+/// # Safety
+///
+/// Safe when used with a request generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn request_get_client_san(
+    req: *const CRequest,
+    index: libc::size_t,
+) -> *const libc::c_char {
     let request = match req.as_ref() {
         Some(r) => r,
         None => {
             return ptr::null();
         }
     };
-    request.body.as_ptr()
+    match request.client_sans.get(index) {
+        Some(s) => s.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Response a plugin can fill in. Anything left unset falls back to the
+/// server's default response.
+#[derive(Default)]
+pub struct CResponse {
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+}
+
+impl CResponse {
+    pub fn new() -> Self {
+        CResponse::default()
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = Some(status);
+    }
+
+    /// Set a header, replacing any value previously set under the same name
+    /// compared case-insensitively.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = Some(body);
+    }
+
+    /// Whether the plugin set anything at all.
+    pub fn is_set(&self) -> bool {
+        self.status.is_some() || !self.headers.is_empty() || self.body.is_some()
+    }
+}
+
+/// Returns 0 on success or -1 if `status` is not between 100 and 999.
+///
+/// # Safety
+///
+/// Safe when used with a response generated by Miss Demeanor only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn response_set_status(
+    resp: *mut CResponse,
+    status: libc::c_int,
+) -> libc::c_int {
+    let response = match resp.as_mut() {
+        Some(r) => r,
+        None => {
+            return -1;
+        }
+    };
+    if !(100..1000).contains(&status) {
+        return -1;
+    }
+    response.set_status(status as u16);
+    0
+}
+
+/// Returns 0 on success or -1 if `name` or `value` is null or not UTF-8.
+///
+/// # Safety
+///
+/// Safe when used with a response generated by Miss Demeanor and
+/// null terminated strings for `name` and `value` only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn response_set_header(
+    resp: *mut CResponse,
+    name: *const libc::c_char,
+    value: *const libc::c_char,
+) -> libc::c_int {
+    let response = match resp.as_mut() {
+        Some(r) => r,
+        None => {
+            return -1;
+        }
+    };
+    if name.is_null() || value.is_null() {
+        return -1;
+    }
+    match (CStr::from_ptr(name).to_str(), CStr::from_ptr(value).to_str()) {
+        (Ok(n), Ok(v)) => {
+            response.set_header(n, v);
+            0
+        }
+        (_, _) => -1,
+    }
+}
+
+/// Copies `len` bytes from `data` into the response body. Returns 0 on
+/// success or -1 if `data` is null and `len` is not 0.
+///
+/// # Safety
+///
+/// Safe when used with a response generated by Miss Demeanor and
+/// `len` readable bytes at `data` only
+#[allow(dead_code)]
+#[no_mangle]
+pub unsafe extern "C" fn response_set_body(
+    resp: *mut CResponse,
+    data: *const u8,
+    len: libc::size_t,
+) -> libc::c_int {
+    let response = match resp.as_mut() {
+        Some(r) => r,
+        None => {
+            return -1;
+        }
+    };
+    if data.is_null() {
+        if len != 0 {
+            return -1;
+        }
+        response.set_body(Vec::new());
+        return 0;
+    }
+    response.set_body(slice::from_raw_parts(data, len).to_vec());
+    0
 }
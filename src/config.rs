@@ -8,6 +8,8 @@ use std::io::Read;
 
 use serde::Deserialize;
 
+use crate::err::DemeanorError;
+
 pub trait PluginConfig {
     fn get_plugin_path(&self) -> &str;
 }
@@ -17,7 +19,8 @@ pub trait PluginConfig {
 pub enum ServerType {
     Webhook,
     UnixSocket,
-    UnknownServerType,
+    Systemd,
+    Unknown,
 }
 
 impl From<String> for ServerType {
@@ -25,23 +28,319 @@ impl From<String> for ServerType {
         match v.as_str() {
             "webhook" => ServerType::Webhook,
             "unix_socket" => ServerType::UnixSocket,
-            _ => ServerType::UnknownServerType,
+            "systemd" => ServerType::Systemd,
+            _ => ServerType::Unknown,
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq, Clone)]
+#[serde(from = "String")]
+pub enum Protocol {
+    Http1,
+    Http2,
+    Unknown(String),
+}
+
+impl From<String> for Protocol {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "http1" => Protocol::Http1,
+            "http2" => Protocol::Http2,
+            _ => Protocol::Unknown(v),
         }
     }
 }
 
+impl Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Protocol::Http1 => write!(f, "HTTP/1.1"),
+            Protocol::Http2 => write!(f, "HTTP/2"),
+            Protocol::Unknown(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq, Default)]
+#[serde(from = "String")]
+pub enum TlsBackend {
+    #[default]
+    NativeTls,
+    Rustls,
+    Unknown(String),
+}
+
+impl From<String> for TlsBackend {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "native_tls" => TlsBackend::NativeTls,
+            "rustls" => TlsBackend::Rustls,
+            _ => TlsBackend::Unknown(v),
+        }
+    }
+}
+
+impl Display for TlsBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TlsBackend::NativeTls => write!(f, "native-tls"),
+            TlsBackend::Rustls => write!(f, "rustls"),
+            TlsBackend::Unknown(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+fn default_protocols() -> Vec<Protocol> {
+    vec![Protocol::Http1, Protocol::Http2]
+}
+
 #[derive(Deserialize, PartialEq, Eq)]
 pub struct Server {
     pub server_type: ServerType,
     pub listen_addr: String,
     pub use_tls: bool,
+    #[serde(default)]
+    pub tls_backend: TlsBackend,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    pub socket_mode: Option<u32>,
+    pub socket_owner: Option<String>,
+    pub socket_group: Option<String>,
+    #[serde(default = "default_protocols")]
+    pub protocols: Vec<Protocol>,
     pub endpoints: HashSet<Endpoint>,
 }
 
+impl Server {
+    /// The first setting of the listener itself that differs from `other`.
+    /// These are fixed once the listener is bound; only the endpoints can be
+    /// reloaded.
+    pub fn changed_listener_setting(&self, other: &Server) -> Option<&'static str> {
+        if self.server_type != other.server_type {
+            Some("server_type")
+        } else if self.use_tls != other.use_tls {
+            Some("use_tls")
+        } else if self.tls_backend != other.tls_backend {
+            Some("tls_backend")
+        } else if self.tls_cert_path != other.tls_cert_path {
+            Some("tls_cert_path")
+        } else if self.tls_key_path != other.tls_key_path {
+            Some("tls_key_path")
+        } else if self.tls_client_ca_path != other.tls_client_ca_path {
+            Some("tls_client_ca_path")
+        } else if self.socket_mode != other.socket_mode {
+            Some("socket_mode")
+        } else if self.socket_owner != other.socket_owner {
+            Some("socket_owner")
+        } else if self.socket_group != other.socket_group {
+            Some("socket_group")
+        } else if self.protocols != other.protocols {
+            Some("protocols")
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Deserialize, Eq)]
 pub struct Endpoint {
     pub path: String,
-    pub trigger_name: String,
+    pub trigger_name: Option<String>,
+    #[serde(default)]
+    pub trigger_names: Vec<String>,
+    #[serde(default)]
+    pub execution: Execution,
+    #[serde(default)]
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    pub prefix: bool,
+    pub methods: Option<Vec<String>>,
+    pub verify: Option<Verification>,
+    pub dedup: Option<Dedup>,
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Clone)]
+#[serde(from = "String")]
+pub enum Provider {
+    GitHub,
+    Gitea,
+    GitLab,
+    Slack,
+    StandardWebhooks,
+    Unknown(String),
+}
+
+impl From<String> for Provider {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "github" => Provider::GitHub,
+            "gitea" => Provider::Gitea,
+            "gitlab" => Provider::GitLab,
+            "slack" => Provider::Slack,
+            "standard_webhooks" => Provider::StandardWebhooks,
+            _ => Provider::Unknown(v),
+        }
+    }
+}
+
+impl Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Provider::GitHub => write!(f, "github"),
+            Provider::Gitea => write!(f, "gitea"),
+            Provider::GitLab => write!(f, "gitlab"),
+            Provider::Slack => write!(f, "slack"),
+            Provider::StandardWebhooks => write!(f, "standard_webhooks"),
+            Provider::Unknown(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq)]
+pub struct Verification {
+    pub provider: Provider,
+    pub secret_file: Option<String>,
+    #[serde(default)]
+    pub secret_files: Vec<String>,
+    pub secret_env: Option<String>,
+    #[serde(default)]
+    pub secret_envs: Vec<String>,
+    #[serde(default = "default_tolerance")]
+    pub tolerance: u64,
+}
+
+fn default_tolerance() -> u64 {
+    300
+}
+
+#[derive(Deserialize, PartialEq, Eq)]
+pub struct Dedup {
+    pub header: Option<String>,
+    #[serde(default = "default_dedup_ttl")]
+    pub ttl: u64,
+    #[serde(default = "default_dedup_capacity")]
+    pub capacity: usize,
+    pub persist_path: Option<String>,
+    #[serde(default = "default_dedup_status")]
+    pub status: u16,
+}
+
+fn default_dedup_ttl() -> u64 {
+    86400
+}
+
+fn default_dedup_capacity() -> usize {
+    10000
+}
+
+fn default_dedup_status() -> u16 {
+    200
+}
+
+#[derive(Deserialize, PartialEq, Eq)]
+pub struct Filter {
+    pub header: Option<String>,
+    pub field: Option<String>,
+    pub equals: Option<String>,
+    pub regex: Option<String>,
+    pub exists: Option<bool>,
+    pub one_of: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Default)]
+#[serde(from = "String")]
+pub enum Execution {
+    #[default]
+    Concurrent,
+    Sequential,
+    Unknown(String),
+}
+
+impl From<String> for Execution {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "concurrent" => Execution::Concurrent,
+            "sequential" => Execution::Sequential,
+            _ => Execution::Unknown(v),
+        }
+    }
+}
+
+impl Display for Execution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Execution::Concurrent => write!(f, "concurrent"),
+            Execution::Sequential => write!(f, "sequential"),
+            Execution::Unknown(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq, Default)]
+#[serde(from = "String")]
+pub enum Aggregation {
+    #[default]
+    All,
+    Any,
+    FirstFailure,
+    Unknown(String),
+}
+
+impl From<String> for Aggregation {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "all" => Aggregation::All,
+            "any" => Aggregation::Any,
+            "first_failure" => Aggregation::FirstFailure,
+            _ => Aggregation::Unknown(v),
+        }
+    }
+}
+
+impl Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Aggregation::All => write!(f, "all"),
+            Aggregation::Any => write!(f, "any"),
+            Aggregation::FirstFailure => write!(f, "first_failure"),
+            Aggregation::Unknown(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Every trigger run for this endpoint in configuration order.
+    pub fn triggers(&self) -> impl Iterator<Item = &String> {
+        self.trigger_name.iter().chain(self.trigger_names.iter())
+    }
+
+    /// Methods sent in the `Allow` header, or `None` if any method is accepted.
+    /// `OPTIONS` is always allowed as it is answered by the server.
+    pub fn allow(&self) -> Option<String> {
+        self.methods.as_ref().map(|methods| {
+            let mut allow = methods
+                .iter()
+                .map(|m| m.to_ascii_uppercase())
+                .collect::<Vec<_>>();
+            if !allow.iter().any(|m| m == "OPTIONS") {
+                allow.push("OPTIONS".to_string());
+            }
+            allow.join(", ")
+        })
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        match self.methods {
+            Some(ref methods) => methods.iter().any(|m| m.eq_ignore_ascii_case(method)),
+            None => true,
+        }
+    }
 }
 
 impl Borrow<String> for Endpoint {
@@ -65,10 +364,90 @@ impl Hash for Endpoint {
     }
 }
 
-#[derive(Deserialize, Eq)]
+#[derive(Deserialize)]
 pub struct Trigger {
     pub name: String,
     pub plugin_path: String,
+    /// Filled in from the top-level `trigger_type` if not set.
+    pub trigger_type: Option<TriggerType>,
+    #[serde(default)]
+    pub input: InputMode,
+    pub timeout: Option<u64>,
+    #[serde(default = "default_timeout_grace_period")]
+    pub timeout_grace_period: u64,
+    /// Free-form settings handed to a C ABI plugin's `trigger_init` as JSON.
+    pub config: Option<toml::Table>,
+    #[serde(default)]
+    pub isolation: Isolation,
+}
+
+fn default_timeout_grace_period() -> u64 {
+    5
+}
+
+/// How an interpreted plugin receives the request.
+#[derive(Deserialize, PartialEq, Eq, Default)]
+#[serde(from = "String")]
+pub enum InputMode {
+    #[default]
+    Argv,
+    Json,
+    Cgi,
+    Unknown(String),
+}
+
+impl From<String> for InputMode {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "argv" => InputMode::Argv,
+            "json" => InputMode::Json,
+            "cgi" => InputMode::Cgi,
+            _ => InputMode::Unknown(v),
+        }
+    }
+}
+
+impl Display for InputMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputMode::Argv => write!(f, "argv"),
+            InputMode::Json => write!(f, "json"),
+            InputMode::Cgi => write!(f, "cgi"),
+            InputMode::Unknown(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Where a C ABI plugin runs.
+#[derive(Deserialize, PartialEq, Eq, Default)]
+#[serde(from = "String")]
+pub enum Isolation {
+    /// Loaded into the server process.
+    #[default]
+    None,
+    /// Loaded into a supervised worker subprocess.
+    Process,
+    Unknown(String),
+}
+
+impl From<String> for Isolation {
+    fn from(v: String) -> Self {
+        match v.as_str() {
+            "none" => Isolation::None,
+            "process" => Isolation::Process,
+            _ => Isolation::Unknown(v),
+        }
+    }
+}
+
+impl Display for Isolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Isolation::None => write!(f, "none"),
+            Isolation::Process => write!(f, "process"),
+            Isolation::Unknown(ref s) => write!(f, "{}", s),
+        }
+    }
 }
 
 impl PluginConfig for Trigger {
@@ -77,6 +456,37 @@ impl PluginConfig for Trigger {
     }
 }
 
+fn toml_to_json(value: &toml::Value) -> serde_json::Value {
+    match *value {
+        toml::Value::String(ref s) => serde_json::Value::from(s.as_str()),
+        toml::Value::Integer(i) => serde_json::Value::from(i),
+        toml::Value::Float(f) => serde_json::Value::from(f),
+        toml::Value::Boolean(b) => serde_json::Value::from(b),
+        toml::Value::Datetime(ref d) => serde_json::Value::from(d.to_string()),
+        toml::Value::Array(ref a) => a.iter().map(toml_to_json).collect(),
+        toml::Value::Table(ref t) => table_to_json(t),
+    }
+}
+
+fn table_to_json(table: &toml::Table) -> serde_json::Value {
+    serde_json::Value::Object(
+        table
+            .iter()
+            .map(|(k, v)| (k.clone(), toml_to_json(v)))
+            .collect(),
+    )
+}
+
+impl Trigger {
+    /// The `[triggers.config]` table as a JSON object, empty if not set.
+    pub fn config_json(&self) -> String {
+        match self.config {
+            Some(ref table) => table_to_json(table).to_string(),
+            None => "{}".to_string(),
+        }
+    }
+}
+
 impl Borrow<String> for Trigger {
     fn borrow(&self) -> &String {
         &self.name
@@ -89,6 +499,8 @@ impl PartialEq<Trigger> for Trigger {
     }
 }
 
+impl Eq for Trigger {}
+
 impl Hash for Trigger {
     fn hash<H>(&self, state: &mut H)
     where
@@ -103,7 +515,7 @@ impl From<String> for TriggerType {
         match v.as_str() {
             "c_abi" => TriggerType::CAbi,
             "interpreted" => TriggerType::Interpreted,
-            _ => TriggerType::UnknownTriggerType(v),
+            _ => TriggerType::Unknown(v),
         }
     }
 }
@@ -113,23 +525,31 @@ impl Display for TriggerType {
         match *self {
             TriggerType::CAbi => write!(f, "C ABI"),
             TriggerType::Interpreted => write!(f, "Interpreted"),
-            TriggerType::UnknownTriggerType(ref s) => write!(f, "{}", s),
+            TriggerType::Unknown(ref s) => write!(f, "{}", s),
         }
     }
 }
 
-#[derive(Deserialize, PartialEq, Eq)]
+#[derive(Deserialize, PartialEq, Eq, Clone)]
 #[serde(from = "String")]
 pub enum TriggerType {
     CAbi,
     Interpreted,
-    UnknownTriggerType(String),
+    Unknown(String),
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    30
 }
 
 #[derive(Deserialize)]
 pub struct TomlConfig {
-    pub trigger_type: TriggerType,
-    pub server: Server,
+    pub trigger_type: Option<TriggerType>,
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+    server: Option<Server>,
+    #[serde(default)]
+    pub servers: Vec<Server>,
     pub triggers: HashSet<Trigger>,
 }
 
@@ -138,6 +558,33 @@ pub fn parse_config(file_path: String) -> Result<TomlConfig, Box<dyn Error>> {
     let mut file_string = String::new();
     file.read_to_string(&mut file_string)?;
     let deserializer = toml::Deserializer::new(file_string.as_str());
-    let config = TomlConfig::deserialize(deserializer)?;
+    let mut config = TomlConfig::deserialize(deserializer)?;
+    // A single [server] table is still accepted and treated as the first entry
+    // of [[servers]].
+    if let Some(server) = config.server.take() {
+        config.servers.insert(0, server);
+    }
+    if config.servers.is_empty() {
+        return Err(Box::new(DemeanorError::new(
+            "At least one server must be configured",
+        )));
+    }
+    // The top-level trigger_type is the default for triggers without one.
+    let default_type = config.trigger_type.clone();
+    config.triggers = config
+        .triggers
+        .drain()
+        .map(|mut trigger| {
+            if trigger.trigger_type.is_none() {
+                trigger.trigger_type = Some(default_type.clone().ok_or_else(|| {
+                    DemeanorError::new(format!(
+                        "Trigger {} has no trigger_type and there is no top-level default",
+                        trigger.name
+                    ))
+                })?);
+            }
+            Ok(trigger)
+        })
+        .collect::<Result<_, DemeanorError>>()?;
     Ok(config)
 }
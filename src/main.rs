@@ -10,9 +10,9 @@ mod webhook;
 
 use std::{env, error::Error, fs::File, io::Read, process};
 
-use config::TriggerType;
+use config::TlsBackend;
 use err::DemeanorError;
-use plugins::{CABIPlugin, InterpretedPlugin};
+use plugins::AnyPlugin;
 use webhook::UseTls;
 
 pub struct Args {
@@ -60,32 +60,38 @@ fn parse_opts() -> Result<(webhook::UseTls, String), Box<dyn Error>> {
     Ok(args)
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
+    // The server re-executes itself to host plugins with process isolation.
+    if env::var_os(plugins::WORKER_ENV).is_some() {
+        return Ok(plugins::run_worker()?);
+    }
+    webhook::inherit_listen_fds()?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(serve());
+    // A C ABI call that timed out cannot be stopped and may still be running
+    // on the blocking pool, so exit without waiting for it.
+    runtime.shutdown_background();
+    result
+}
+
+async fn serve() -> Result<(), Box<dyn Error>> {
     let (mut use_tls, config_path) = parse_opts()?;
-    let config = config::parse_config(config_path)?;
-    if config.server.use_tls && !use_tls.use_tls() {
-        error!("Server requires options -f for TLS identity file and PKCS12_PASSWORD environment variable");
+    let config = config::parse_config(config_path.clone())?;
+    let needs_identity = config
+        .servers
+        .iter()
+        .any(|s| s.use_tls && s.tls_backend == TlsBackend::NativeTls);
+    if needs_identity && !use_tls.use_tls() {
+        error!("Server configuration requires options -f for TLS identity file and PKCS12_PASSWORD environment variable");
         return Err(
             Box::new(DemeanorError::new("Missing required options for TLS")) as Box<dyn Error>,
         );
-    } else if !config.server.use_tls && use_tls.use_tls() {
-        error!("Server specified no TLS but TLS CLI parameters were provided; ignoring");
+    } else if !needs_identity && use_tls.use_tls() {
+        error!("No server uses a PKCS12 identity but TLS CLI parameters were provided; ignoring");
         use_tls = UseTls::No;
     }
 
-    if let TriggerType::CAbi = config.trigger_type {
-        let server = webhook::WebhookServer::<CABIPlugin>::new(use_tls, config)?;
-        server.serve().await
-    } else if let TriggerType::Interpreted = config.trigger_type {
-        let server = webhook::WebhookServer::<InterpretedPlugin>::new(use_tls, config)?;
-        server.serve().await
-    } else {
-        error!("Unrecognized trigger type: {}", config.trigger_type);
-        Err(Box::new(DemeanorError::new(format!(
-            "Unrecognized trigger type: {}",
-            config.trigger_type
-        ))))
-    }
+    let server = webhook::WebhookServer::<AnyPlugin>::new(use_tls, config_path, config)?;
+    server.serve().await
 }
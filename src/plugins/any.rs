@@ -0,0 +1,126 @@
+use std::{
+    borrow::Borrow,
+    hash::{Hash, Hasher},
+    io,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+
+use missdemeanor::{CRequest, CResponse};
+
+use crate::{
+    config::{InputMode, Isolation, Trigger, TriggerType},
+    plugins::{err::PluginError, CABIPlugin, InterpretedPlugin, NewPlugin, Plugin, ProcessPlugin},
+};
+
+/// A trigger plugin of whichever type its config entry asks for, so one
+/// server can mix C ABI and interpreted triggers.
+pub enum AnyPlugin {
+    CAbi(CABIPlugin),
+    Process(ProcessPlugin),
+    Interpreted(InterpretedPlugin),
+}
+
+impl AnyPlugin {
+    fn config(&self) -> &Trigger {
+        match *self {
+            AnyPlugin::CAbi(ref p) => &p.config,
+            AnyPlugin::Process(ref p) => &p.config,
+            AnyPlugin::Interpreted(ref p) => &p.config,
+        }
+    }
+}
+
+impl NewPlugin for AnyPlugin {
+    fn new(config: Trigger) -> Result<Self, io::Error> {
+        match config.trigger_type {
+            Some(TriggerType::CAbi) => {
+                if config.input != InputMode::Argv {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Trigger {}: input only applies to interpreted triggers",
+                            config.name
+                        ),
+                    ));
+                }
+                match config.isolation {
+                    Isolation::None => Ok(AnyPlugin::CAbi(CABIPlugin::new(config)?)),
+                    Isolation::Process => Ok(AnyPlugin::Process(ProcessPlugin::new(config)?)),
+                    Isolation::Unknown(ref i) => Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Trigger {}: isolation {} not recognized", config.name, i),
+                    )),
+                }
+            }
+            Some(TriggerType::Interpreted) => {
+                if config.config.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Trigger {}: config only applies to c_abi triggers",
+                            config.name
+                        ),
+                    ));
+                }
+                if config.isolation != Isolation::None {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Trigger {}: isolation only applies to c_abi triggers",
+                            config.name
+                        ),
+                    ));
+                }
+                Ok(AnyPlugin::Interpreted(InterpretedPlugin::new(config)?))
+            }
+            Some(TriggerType::Unknown(ref t)) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Trigger {}: trigger type {} not recognized", config.name, t),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Trigger {} has no trigger type", config.name),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for AnyPlugin {
+    async fn run_trigger(
+        &self,
+        request: Arc<CRequest>,
+        response: &mut CResponse,
+    ) -> Result<(), PluginError> {
+        match *self {
+            AnyPlugin::CAbi(ref p) => p.run_trigger(request, response).await,
+            AnyPlugin::Process(ref p) => p.run_trigger(request, response).await,
+            AnyPlugin::Interpreted(ref p) => p.run_trigger(request, response).await,
+        }
+    }
+}
+
+impl Hash for AnyPlugin {
+    fn hash<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        self.config().hash(hasher)
+    }
+}
+
+impl PartialEq for AnyPlugin {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.config() == rhs.config()
+    }
+}
+
+impl Eq for AnyPlugin {}
+
+impl Borrow<String> for AnyPlugin {
+    fn borrow(&self) -> &String {
+        self.config().borrow()
+    }
+}
@@ -1,47 +1,287 @@
 use std::{
     borrow::Borrow,
+    collections::BTreeMap,
+    env,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
     hash::{Hash, Hasher},
     io,
+    os::{
+        raw::{c_char, c_void},
+        unix::fs::{MetadataExt, OpenOptionsExt},
+    },
+    path::Path,
+    process, ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use libloading::{Library, Symbol};
+use async_trait::async_trait;
+use libloading::Library;
+use tokio::time;
 
-use missdemeanor::CRequest;
+use missdemeanor::{CRequest, CResponse, ABI_VERSION};
 
 use crate::{
     config::Trigger,
     plugins::{err::PluginError, NewPlugin, Plugin},
 };
 
+// Plugins written against the shorter signatures ignore the response and
+// state pointers.
+type TriggerFn = unsafe extern "C" fn(*const CRequest, *mut CResponse, *mut c_void) -> libc::c_int;
+type InitFn = unsafe extern "C" fn(*const c_char, *mut *mut c_void) -> libc::c_int;
+type DestroyFn = unsafe extern "C" fn(*mut c_void);
+
+/// Oldest plugin ABI version this host still supports. Plugins that do not
+/// export `miss_demeanor_abi_version` are assumed to be built against it.
+const MIN_ABI_VERSION: u32 = 1;
+
+static COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// Device, inode, modification time and size of a plugin file.
+type FileId = (u64, u64, i64, i64, u64);
+
+/// The `FileId` each plugin path had when it was last loaded.
+static LOADED_FILES: Mutex<BTreeMap<String, FileId>> = Mutex::new(BTreeMap::new());
+
+fn file_id(path: &str) -> Result<FileId, io::Error> {
+    let m = fs::metadata(path)?;
+    Ok((m.dev(), m.ino(), m.mtime(), m.mtime_nsec(), m.size()))
+}
+
+/// Open the plugin at `plugin_path`. `dlopen` hands back the library already
+/// loaded from a path, even if the file has since been rebuilt, so a plugin
+/// that changed since it was last loaded is opened from a private copy.
+fn open_plugin(plugin_path: &str) -> Result<Library, io::Error> {
+    let id = file_id(plugin_path)?;
+    let changed = {
+        let mut loaded = match LOADED_FILES.lock() {
+            Ok(l) => l,
+            Err(e) => e.into_inner(),
+        };
+        loaded
+            .insert(plugin_path.to_string(), id)
+            .is_some_and(|prev| prev != id)
+    };
+    if changed {
+        open_copy(plugin_path)
+    } else {
+        unsafe { Library::new(plugin_path) }
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    let mut plugin = File::open(from)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o700)
+        .open(to)?;
+    if let Err(e) = io::copy(&mut plugin, &mut file) {
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Open a copy of the plugin, removed again once it is mapped. The copy is
+/// made next to the plugin, so its `$ORIGIN` and mount options are the same,
+/// and only falls back to the temporary directory if that one is read only.
+fn open_copy(plugin_path: &str) -> Result<Library, io::Error> {
+    let path = Path::new(plugin_path);
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let name = format!(
+        ".miss-demeanor-{}-{}-{}",
+        process::id(),
+        COPIES.fetch_add(1, Ordering::Relaxed),
+        path.file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    );
+    let mut copy = dir.join(&name);
+    if let Err(e) = copy_file(path, &copy) {
+        copy = env::temp_dir().join(&name);
+        warn!(
+            "Could not copy changed plugin {} into {} ({}); loading it from {}",
+            plugin_path,
+            dir.display(),
+            e,
+            copy.display()
+        );
+        copy_file(path, &copy)?;
+    }
+    let result =
+        unsafe { Library::new(&copy) }.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let _ = fs::remove_file(&copy);
+    result
+}
+
+/// A loaded library, its resolved symbols and the state its `trigger_init`
+/// returned. The function pointers are only valid while `lib` is loaded, and
+/// the state is handed to `trigger_destroy` once the last call using it has
+/// finished.
+pub(crate) struct Loaded {
+    trigger: TriggerFn,
+    state: *mut c_void,
+    destroy: Option<DestroyFn>,
+    _lib: Library,
+}
+
+// The state pointer is owned by the plugin, which must accept it being used
+// from any thread.
+unsafe impl Send for Loaded {}
+unsafe impl Sync for Loaded {}
+
+impl Loaded {
+    /// Load the library for trigger `name`, check its ABI version and run
+    /// its `trigger_init` with `config_json`.
+    pub fn load(name: &str, plugin_path: &str, config_json: &str) -> Result<Self, io::Error> {
+        let lib = open_plugin(plugin_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Trigger {}: {}: {}", name, plugin_path, e),
+            )
+        })?;
+        let version = match unsafe { lib.get::<*const u32>(b"miss_demeanor_abi_version\0") } {
+            Ok(version) => unsafe { **version },
+            Err(_) => {
+                warn!(
+                    "Trigger {}: plugin does not export miss_demeanor_abi_version; assuming \
+                     ABI version {}. Rebuild it against the current miss-demeanor-pluginutils \
+                     to have its version checked",
+                    name, MIN_ABI_VERSION
+                );
+                MIN_ABI_VERSION
+            }
+        };
+        if !(MIN_ABI_VERSION..=ABI_VERSION).contains(&version) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Trigger {}: plugin ABI version {} is not supported; expected {} to {}",
+                    name, version, MIN_ABI_VERSION, ABI_VERSION
+                ),
+            ));
+        }
+        let trigger = *unsafe { lib.get::<TriggerFn>(b"trigger\0") }.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Trigger {}: failed to find handler: {}", name, e),
+            )
+        })?;
+        let init = unsafe { lib.get::<InitFn>(b"trigger_init\0") }
+            .ok()
+            .map(|f| *f);
+        let destroy = unsafe { lib.get::<DestroyFn>(b"trigger_destroy\0") }
+            .ok()
+            .map(|f| *f);
+
+        let mut state = ptr::null_mut();
+        if let Some(init) = init {
+            let config_json = CString::new(config_json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let rc = unsafe { init(config_json.as_ptr(), &mut state) };
+            if rc != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Trigger {}: trigger_init failed with {}", name, rc),
+                ));
+            }
+        }
+
+        Ok(Loaded {
+            trigger,
+            state,
+            destroy,
+            _lib: lib,
+        })
+    }
+
+    pub fn call(&self, request: &CRequest, response: &mut CResponse) -> Result<(), PluginError> {
+        match unsafe {
+            (self.trigger)(
+                request as *const CRequest,
+                response as *mut CResponse,
+                self.state,
+            )
+        } {
+            0 => Ok(()),
+            _ => {
+                error!("Plugin exited unsuccessfully");
+                Err(PluginError::new(500, "Internal server error"))
+            }
+        }
+    }
+}
+
+impl Drop for Loaded {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.state) };
+        }
+    }
+}
+
 pub struct CABIPlugin {
-    lib: Library,
+    loaded: Arc<Loaded>,
     pub config: Trigger,
 }
 
 impl NewPlugin for CABIPlugin {
     fn new(config: Trigger) -> Result<Self, io::Error> {
         Ok(CABIPlugin {
-            lib: unsafe { Library::new(&config.plugin_path) }
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            loaded: Arc::new(Loaded::load(
+                &config.name,
+                &config.plugin_path,
+                &config.config_json(),
+            )?),
             config,
         })
     }
 }
 
+#[async_trait]
 impl Plugin for CABIPlugin {
-    fn run_trigger(&self, request: CRequest) -> Result<(), PluginError> {
-        let func: Symbol<unsafe extern "C" fn(*const CRequest) -> libc::c_int> =
-            unsafe { self.lib.get(b"trigger\0") }.map_err(|e| {
-                error!("{}", e);
-                PluginError::new(500, "Failed to find handler")
-            })?;
-        match unsafe { func(&request as *const CRequest) } {
-            i if i == 0 => Ok(()),
-            _ => {
-                error!("Plugin exited unsuccessfully");
-                Err(PluginError::new(500, "Internal server error"))
-            }
-        }
+    /// Plugin code may block, so it runs on the blocking thread pool rather
+    /// than on the thread serving connections.
+    async fn run_trigger(
+        &self,
+        request: Arc<CRequest>,
+        response: &mut CResponse,
+    ) -> Result<(), PluginError> {
+        let loaded = Arc::clone(&self.loaded);
+        let task = tokio::task::spawn_blocking(move || {
+            let mut response = CResponse::new();
+            (loaded.call(&request, &mut response), response)
+        });
+        let joined = match self.config.timeout {
+            // A thread cannot be stopped from outside, so a call that times
+            // out keeps running in the background.
+            Some(timeout) => time::timeout(Duration::from_secs(timeout), task)
+                .await
+                .map_err(|_| {
+                    error!(
+                        "Trigger plugin {} timed out after {} seconds",
+                        self.config.name, timeout
+                    );
+                    PluginError::timeout("Trigger timed out")
+                })?,
+            None => task.await,
+        };
+        let (result, plugin_response) = joined.map_err(|e| {
+            error!("Trigger plugin task failed: {}", e);
+            PluginError::new(500, "Trigger panicked")
+        })?;
+        *response = plugin_response;
+        result
     }
 }
 
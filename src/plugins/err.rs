@@ -4,15 +4,55 @@ use std::fmt::{self, Display};
 use http_body_util::Full;
 use hyper::{body::Bytes, Response, StatusCode};
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PluginErrorKind {
+    Failure,
+    Timeout,
+    Crashed,
+}
+
 #[derive(Debug)]
-pub struct PluginError(u16, String);
+pub struct PluginError(u16, String, PluginErrorKind);
 
 impl PluginError {
     pub fn new<S>(code: u16, body: S) -> Self
     where
         S: Display,
     {
-        PluginError(code, body.to_string())
+        PluginError(code, body.to_string(), PluginErrorKind::Failure)
+    }
+
+    /// The plugin did not finish within its configured timeout.
+    pub fn timeout<S>(body: S) -> Self
+    where
+        S: Display,
+    {
+        PluginError(504, body.to_string(), PluginErrorKind::Timeout)
+    }
+
+    /// The process running the plugin died before it finished.
+    pub fn crashed<S>(body: S) -> Self
+    where
+        S: Display,
+    {
+        PluginError(502, body.to_string(), PluginErrorKind::Crashed)
+    }
+
+    /// The same error with a different message, such as the explanation the
+    /// plugin gave in its response.
+    pub fn with_message<S>(self, body: S) -> Self
+    where
+        S: Display,
+    {
+        PluginError(self.0, body.to_string(), self.2)
+    }
+
+    pub fn message(&self) -> &str {
+        &self.1
+    }
+
+    pub fn kind(&self) -> PluginErrorKind {
+        self.2
     }
 
     pub fn into_response(self) -> Response<Full<Bytes>> {
@@ -25,7 +65,11 @@ impl PluginError {
 
 impl Display for PluginError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{}", self.0)
+        match self.2 {
+            PluginErrorKind::Failure => write!(f, "{}", self.0),
+            PluginErrorKind::Timeout => write!(f, "{} (timed out)", self.0),
+            PluginErrorKind::Crashed => write!(f, "{} (crashed)", self.0),
+        }
     }
 }
 
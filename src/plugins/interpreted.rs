@@ -1,17 +1,93 @@
 use std::{
     borrow::Borrow,
+    collections::HashMap,
+    ffi::OsStr,
     hash::{Hash, Hasher},
-    io,
-    process::Command,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
 };
 
-use missdemeanor::CRequest;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{Map, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::{Child, Command},
+    time,
+};
+
+use missdemeanor::{CRequest, CResponse};
 
 use crate::{
-    config::{PluginConfig, Trigger},
+    config::{InputMode, PluginConfig, Trigger},
     plugins::{err::PluginError, NewPlugin, Plugin},
 };
 
+/// Response a script can print to stdout. Output that is not exactly this
+/// JSON object is passed through to the server's stdout instead.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Envelope {
+    status: Option<u16>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    body: Option<String>,
+}
+
+fn apply_output(stdout: &[u8], response: &mut CResponse) {
+    match serde_json::from_slice::<Envelope>(stdout) {
+        Ok(envelope) => {
+            if let Some(status) = envelope.status {
+                response.set_status(status);
+            }
+            for (name, value) in envelope.headers.iter() {
+                response.set_header(name, value);
+            }
+            if let Some(body) = envelope.body {
+                response.set_body(body.into_bytes());
+            }
+        }
+        Err(_) => {
+            if let Err(e) = io::stdout().write_all(stdout) {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Wait for the child to exit while collecting its stdout.
+async fn wait_with_output(child: &mut Child) -> Result<(ExitStatus, Vec<u8>), io::Error> {
+    let mut stdout = Vec::new();
+    match child.stdout.take() {
+        Some(mut pipe) => {
+            let (read, status) = tokio::join!(pipe.read_to_end(&mut stdout), child.wait());
+            read?;
+            Ok((status?, stdout))
+        }
+        None => Ok((child.wait().await?, stdout)),
+    }
+}
+
+/// Stop a child that ran past its timeout: SIGTERM its process group, then
+/// SIGKILL it if it has not exited after `grace`.
+async fn terminate(child: &mut Child, grace: Duration) {
+    let pgid = match child.id() {
+        Some(pid) => pid as libc::pid_t,
+        None => return,
+    };
+    unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    if time::timeout(grace, child.wait()).await.is_err() {
+        warn!("Plugin did not exit after SIGTERM; sending SIGKILL");
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+        if let Err(e) = child.wait().await {
+            error!("{}", e);
+        }
+    }
+}
+
 pub struct InterpretedPlugin {
     cmd: String,
     pub config: Trigger,
@@ -19,6 +95,12 @@ pub struct InterpretedPlugin {
 
 impl NewPlugin for InterpretedPlugin {
     fn new(config: Trigger) -> Result<Self, io::Error> {
+        if let InputMode::Unknown(ref m) = config.input {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Input mode {} not recognized", m),
+            ));
+        }
         Ok(InterpretedPlugin {
             cmd: config.get_plugin_path().to_string(),
             config,
@@ -26,45 +108,195 @@ impl NewPlugin for InterpretedPlugin {
     }
 }
 
+/// Pass the method, URI, headers JSON and body as arguments.
+fn argv_input(cmd: &mut Command, request: &CRequest) -> Result<(), PluginError> {
+    // Arguments are C strings, so a body with a NUL byte cannot be passed.
+    if request.get_body_bytes().contains(&0) {
+        error!("Request body contains a NUL byte");
+        return Err(PluginError::new(400, "Bad body"));
+    }
+    cmd.arg(request.get_method().map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Bad method")
+    })?)
+    .arg(request.get_uri().map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Bad URI")
+    })?)
+    .arg(request.get_headers().map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Bad headers")
+    })?)
+    .arg(OsStr::from_bytes(request.get_body_bytes()));
+    Ok(())
+}
+
+/// A JSON document with the method, URI, headers and body, for stdin. Bodies
+/// that are not UTF-8 are sent base64 encoded as `body_base64`.
+fn json_input(request: &CRequest) -> Result<Vec<u8>, PluginError> {
+    let mut document = Map::new();
+    document.insert(
+        "method".to_string(),
+        Value::from(request.get_method().map_err(|e| {
+            error!("{}", e);
+            PluginError::new(400, "Bad method")
+        })?),
+    );
+    document.insert(
+        "uri".to_string(),
+        Value::from(request.get_uri().map_err(|e| {
+            error!("{}", e);
+            PluginError::new(400, "Bad URI")
+        })?),
+    );
+    document.insert(
+        "headers".to_string(),
+        Value::from(request.get_headers_map().map_err(|e| {
+            error!("{}", e);
+            PluginError::new(400, "Bad headers")
+        })?),
+    );
+    match request.get_body() {
+        Ok(body) => document.insert("body".to_string(), Value::from(body)),
+        Err(_) => document.insert(
+            "body_base64".to_string(),
+            Value::from(STANDARD.encode(request.get_body_bytes())),
+        ),
+    };
+    serde_json::to_vec(&document).map_err(|e| {
+        error!("{}", e);
+        PluginError::new(500, "Internal server error")
+    })
+}
+
+/// Set CGI/1.1 style environment variables; the body goes to stdin.
+fn cgi_input(cmd: &mut Command, request: &CRequest) -> Result<(), PluginError> {
+    let uri = request.get_uri().map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Bad URI")
+    })?;
+    cmd.env("GATEWAY_INTERFACE", "CGI/1.1")
+        .env(
+            "REQUEST_METHOD",
+            OsStr::from_bytes(request.method.as_bytes()),
+        )
+        .env("REQUEST_URI", uri)
+        .env("PATH_INFO", OsStr::from_bytes(request.path.as_bytes()))
+        .env("QUERY_STRING", OsStr::from_bytes(request.query.as_bytes()))
+        .env("CONTENT_LENGTH", request.get_body_bytes().len().to_string());
+    let headers = request.get_headers_map().map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Bad headers")
+    })?;
+    for (name, value) in headers.iter() {
+        let value = value.as_str().unwrap_or_default();
+        match name.as_str() {
+            "content-type" => {
+                cmd.env("CONTENT_TYPE", value);
+            }
+            "content-length" => (),
+            // A Proxy header would become HTTP_PROXY and redirect the
+            // plugin's own outgoing requests.
+            "proxy" => (),
+            _ => {
+                cmd.env(
+                    format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_")),
+                    value,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
 impl Plugin for InterpretedPlugin {
-    fn run_trigger(&self, request: CRequest) -> Result<(), PluginError> {
-        let mut cmd = Command::new(self.cmd.as_str())
-            .arg(request.get_method().map_err(|e| {
-                error!("{}", e);
-                PluginError::new(400, "Bad method")
-            })?)
-            .arg(request.get_uri().map_err(|e| {
-                error!("{}", e);
-                PluginError::new(400, "Bad URI")
-            })?)
-            .arg(request.get_headers().map_err(|e| {
-                error!("{}", e);
-                PluginError::new(400, "Bad headers")
-            })?)
-            .arg(request.get_body().map_err(|e| {
-                error!("{}", e);
-                PluginError::new(400, "Bad body")
-            })?)
-            .spawn()
-            .map_err(|e| {
+    async fn run_trigger(
+        &self,
+        request: Arc<CRequest>,
+        response: &mut CResponse,
+    ) -> Result<(), PluginError> {
+        let mut cmd = Command::new(self.cmd.as_str());
+        let stdin = match self.config.input {
+            InputMode::Json => Some(json_input(&request)?),
+            InputMode::Cgi => {
+                cgi_input(&mut cmd, &request)?;
+                Some(request.get_body_bytes().to_vec())
+            }
+            _ => {
+                argv_input(&mut cmd, &request)?;
+                None
+            }
+        };
+        if stdin.is_some() {
+            cmd.stdin(Stdio::piped());
+        }
+        // Run in a new process group so a Ctrl-C aimed at the server does not
+        // interrupt the script before in-flight requests have drained, and so
+        // anything the script started can be stopped along with it on timeout.
+        cmd.stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .process_group(0);
+        let mut child = cmd.spawn().map_err(|e| {
+            error!("{}", e);
+            PluginError::new(500, "Internal server error")
+        })?;
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            // Written from a separate task so a script that prints before
+            // reading all of its input cannot deadlock on a full pipe.
+            tokio::spawn(async move {
+                match pipe.write_all(&input).await {
+                    Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+                    Err(e) => error!("Failed to write plugin input: {}", e),
+                    Ok(()) => (),
+                }
+            });
+        }
+
+        // Waited on in its own task so the timeout is still enforced, and the
+        // child reaped, if the request is dropped because the client went away.
+        let name = self.config.name.clone();
+        let timeout = self.config.timeout;
+        let grace = Duration::from_secs(self.config.timeout_grace_period);
+        let waiter = tokio::spawn(async move {
+            let output = match timeout {
+                Some(timeout) => {
+                    match time::timeout(Duration::from_secs(timeout), wait_with_output(&mut child))
+                        .await
+                    {
+                        Ok(output) => output,
+                        Err(_) => {
+                            error!(
+                                "Trigger plugin {} timed out after {} seconds",
+                                name, timeout
+                            );
+                            terminate(&mut child, grace).await;
+                            return Err(PluginError::timeout("Trigger timed out"));
+                        }
+                    }
+                }
+                None => wait_with_output(&mut child).await,
+            };
+            output.map_err(|e| {
                 error!("{}", e);
                 PluginError::new(500, "Internal server error")
-            })?;
-        let status = cmd.wait();
-        match status.map(|s| s.code()) {
-            Ok(Some(0)) => Ok(()),
-            Ok(Some(_)) => {
+            })
+        });
+        let (status, stdout) = waiter.await.map_err(|e| {
+            error!("Trigger plugin task failed: {}", e);
+            PluginError::new(500, "Trigger panicked")
+        })??;
+        apply_output(&stdout, response);
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(_) => {
                 error!("Plugin exited unsuccessfully");
                 Err(PluginError::new(500, "Internal server error"))
             }
-            Ok(None) => {
+            None => {
                 error!("No status code returned");
                 Err(PluginError::new(500, "Internal server error"))
             }
-            Err(e) => {
-                error!("{}", e);
-                Err(PluginError::new(500, "Internal server error"))
-            }
         }
     }
 }
@@ -0,0 +1,548 @@
+use std::{
+    ffi::CString,
+    io::{self, Read, Write},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use missdemeanor::{Body, CRequest, CResponse};
+
+/// Frames larger than this are treated as a corrupt stream.
+const MAX_FRAME_LEN: usize = 1 << 30;
+
+// Messages exchanged with a plugin worker process are sent as frames: a
+// big-endian u32 length followed by that many bytes, the first of which is one
+// of these tags. Variable length fields are themselves prefixed with a
+// big-endian u32 length.
+const INIT: u8 = 0;
+const CALL: u8 = 1;
+const READY: u8 = 2;
+const INIT_FAILED: u8 = 3;
+const RESULT: u8 = 4;
+
+/// Sent by the server.
+pub(crate) enum ToWorker {
+    /// Load the plugin; always the first message.
+    Init {
+        name: String,
+        plugin_path: String,
+        config_json: String,
+    },
+    /// Run the trigger for a request.
+    Call { id: u64, request: CRequest },
+}
+
+/// Sent by the worker.
+pub(crate) enum FromWorker {
+    Ready,
+    InitFailed(String),
+    Result {
+        id: u64,
+        success: bool,
+        response: CResponse,
+    },
+}
+
+fn invalid<S>(msg: S) -> io::Error
+where
+    S: Into<String>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn len(&mut self, v: usize) {
+        self.0.extend_from_slice(&(v as u32).to_be_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.len(v.len());
+        self.0.extend_from_slice(v);
+    }
+
+    fn opt_bytes(&mut self, v: Option<&[u8]>) {
+        match v {
+            Some(b) => {
+                self.u8(1);
+                self.bytes(b);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn c_strings(&mut self, v: &[CString]) {
+        self.len(v.len());
+        for s in v {
+            self.bytes(s.as_bytes());
+        }
+    }
+
+    fn c_string_pairs(&mut self, v: &[(CString, CString)]) {
+        self.len(v.len());
+        for (k, v) in v {
+            self.bytes(k.as_bytes());
+            self.bytes(v.as_bytes());
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if self.0.len() < len {
+            return Err(invalid("Truncated worker message"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, io::Error> {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn u32(&mut self) -> Result<u32, io::Error> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, io::Error> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], io::Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn opt_bytes(&mut self) -> Result<Option<&'a [u8]>, io::Error> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => self.bytes().map(Some),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, io::Error> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn c_string(&mut self) -> Result<CString, io::Error> {
+        CString::new(self.bytes()?).map_err(|e| invalid(e.to_string()))
+    }
+
+    fn c_strings(&mut self) -> Result<Vec<CString>, io::Error> {
+        (0..self.u32()?).map(|_| self.c_string()).collect()
+    }
+
+    fn c_string_pairs(&mut self) -> Result<Vec<(CString, CString)>, io::Error> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.c_string()?, self.c_string()?)))
+            .collect()
+    }
+}
+
+impl ToWorker {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        match *self {
+            ToWorker::Init {
+                ref name,
+                ref plugin_path,
+                ref config_json,
+            } => {
+                e.u8(INIT);
+                e.bytes(name.as_bytes());
+                e.bytes(plugin_path.as_bytes());
+                e.bytes(config_json.as_bytes());
+            }
+            ToWorker::Call { id, ref request } => {
+                e.u8(CALL);
+                e.u64(id);
+                e.bytes(request.method.as_bytes());
+                e.bytes(request.uri.as_bytes());
+                e.bytes(request.path.as_bytes());
+                e.bytes(request.query.as_bytes());
+                e.c_string_pairs(&request.path_params);
+                e.c_string_pairs(&request.query_params);
+                e.c_string_pairs(&request.headers);
+                e.bytes(request.body.as_bytes());
+                e.opt_bytes(request.client_subject.as_ref().map(|s| s.as_bytes()));
+                e.c_strings(&request.client_sans);
+            }
+        }
+        e.0
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self, io::Error> {
+        let mut d = Decoder(frame);
+        match d.u8()? {
+            INIT => Ok(ToWorker::Init {
+                name: d.string()?,
+                plugin_path: d.string()?,
+                config_json: d.string()?,
+            }),
+            CALL => Ok(ToWorker::Call {
+                id: d.u64()?,
+                request: CRequest {
+                    method: d.c_string()?,
+                    uri: d.c_string()?,
+                    path: d.c_string()?,
+                    query: d.c_string()?,
+                    path_params: d.c_string_pairs()?,
+                    query_params: d.c_string_pairs()?,
+                    headers: d.c_string_pairs()?,
+                    body: Body::new(d.bytes()?.to_vec()),
+                    client_subject: d
+                        .opt_bytes()?
+                        .map(CString::new)
+                        .transpose()
+                        .map_err(|e| invalid(e.to_string()))?,
+                    client_sans: d.c_strings()?,
+                },
+            }),
+            t => Err(invalid(format!("Unknown worker message type {}", t))),
+        }
+    }
+}
+
+impl FromWorker {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        match *self {
+            FromWorker::Ready => e.u8(READY),
+            FromWorker::InitFailed(ref msg) => {
+                e.u8(INIT_FAILED);
+                e.bytes(msg.as_bytes());
+            }
+            FromWorker::Result {
+                id,
+                success,
+                ref response,
+            } => {
+                e.u8(RESULT);
+                e.u64(id);
+                e.u8(success as u8);
+                // The status was validated by response_set_status, so 0
+                // never collides with a real one.
+                e.u16(response.status.unwrap_or(0));
+                e.len(response.headers.len());
+                for (k, v) in response.headers.iter() {
+                    e.bytes(k.as_bytes());
+                    e.bytes(v.as_bytes());
+                }
+                e.opt_bytes(response.body.as_deref());
+            }
+        }
+        e.0
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Self, io::Error> {
+        let mut d = Decoder(frame);
+        match d.u8()? {
+            READY => Ok(FromWorker::Ready),
+            INIT_FAILED => Ok(FromWorker::InitFailed(d.string()?)),
+            RESULT => {
+                let id = d.u64()?;
+                let success = d.u8()? != 0;
+                let status = match d.u16()? {
+                    0 => None,
+                    s => Some(s),
+                };
+                let headers = (0..d.u32()?)
+                    .map(|_| Ok((d.string()?, d.string()?)))
+                    .collect::<Result<Vec<_>, io::Error>>()?;
+                let body = d.opt_bytes()?.map(|b| b.to_vec());
+                Ok(FromWorker::Result {
+                    id,
+                    success,
+                    response: CResponse {
+                        status,
+                        headers,
+                        body,
+                    },
+                })
+            }
+            t => Err(invalid(format!("Unknown worker message type {}", t))),
+        }
+    }
+}
+
+fn check_len(len: u32) -> Result<usize, io::Error> {
+    let len = len as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid(format!(
+            "Worker message of {} bytes is too large",
+            len
+        )));
+    }
+    Ok(len)
+}
+
+/// Read one frame, or `None` if the stream was closed between frames.
+pub(crate) fn read_frame<R>(reader: &mut R) -> Result<Option<Vec<u8>>, io::Error>
+where
+    R: Read,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut frame = vec![0; check_len(u32::from_be_bytes(len))?];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+pub(crate) fn write_frame<W>(writer: &mut W, frame: &[u8]) -> Result<(), io::Error>
+where
+    W: Write,
+{
+    writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+    writer.write_all(frame)?;
+    writer.flush()
+}
+
+/// Read one frame, or `None` if the stream was closed between frames.
+pub(crate) async fn read_frame_async<R>(reader: &mut R) -> Result<Option<Vec<u8>>, io::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut frame = vec![0; check_len(u32::from_be_bytes(len))?];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+pub(crate) async fn write_frame_async<W>(writer: &mut W, frame: &[u8]) -> Result<(), io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer
+        .write_all(&(frame.len() as u32).to_be_bytes())
+        .await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn request(client_subject: Option<CString>) -> CRequest {
+        CRequest {
+            method: c("POST"),
+            uri: c("/hooks/a?x=1"),
+            path: c("/hooks/a"),
+            query: c("x=1"),
+            path_params: vec![(c("name"), c("a"))],
+            query_params: vec![(c("x"), c("1"))],
+            headers: vec![
+                (c("x-tag"), c("one")),
+                (c("x-tag"), c("two")),
+                (c("content-type"), c("application/octet-stream")),
+            ],
+            body: Body::new(vec![0, 1, 0xff, 0, b'a']),
+            client_subject,
+            client_sans: vec![c("a.example.com"), c("b.example.com")],
+        }
+    }
+
+    fn round_trip_call(request: CRequest) -> (u64, CRequest) {
+        let frame = ToWorker::Call { id: 7, request }.encode();
+        match ToWorker::decode(&frame).unwrap() {
+            ToWorker::Call { id, request } => (id, request),
+            ToWorker::Init { .. } => panic!("decoded a call as init"),
+        }
+    }
+
+    fn round_trip_result(response: CResponse) -> (u64, bool, CResponse) {
+        let frame = FromWorker::Result {
+            id: 9,
+            success: true,
+            response,
+        }
+        .encode();
+        match FromWorker::decode(&frame).unwrap() {
+            FromWorker::Result {
+                id,
+                success,
+                response,
+            } => (id, success, response),
+            _ => panic!("decoded a result as another message"),
+        }
+    }
+
+    #[test]
+    fn call_round_trip() {
+        for subject in [None, Some(c("CN=client"))] {
+            let expected = request(subject.clone());
+            let (id, decoded) = round_trip_call(request(subject));
+            assert_eq!(id, 7);
+            assert_eq!(decoded.method, expected.method);
+            assert_eq!(decoded.uri, expected.uri);
+            assert_eq!(decoded.path, expected.path);
+            assert_eq!(decoded.query, expected.query);
+            assert_eq!(decoded.path_params, expected.path_params);
+            assert_eq!(decoded.query_params, expected.query_params);
+            assert_eq!(decoded.headers, expected.headers);
+            assert_eq!(decoded.body.as_bytes(), expected.body.as_bytes());
+            assert_eq!(decoded.client_subject, expected.client_subject);
+            assert_eq!(decoded.client_sans, expected.client_sans);
+        }
+    }
+
+    #[test]
+    fn init_round_trip() {
+        let frame = ToWorker::Init {
+            name: "t".to_string(),
+            plugin_path: "/plugins/t.so".to_string(),
+            config_json: "{\"a\":1}".to_string(),
+        }
+        .encode();
+        match ToWorker::decode(&frame).unwrap() {
+            ToWorker::Init {
+                name,
+                plugin_path,
+                config_json,
+            } => {
+                assert_eq!(name, "t");
+                assert_eq!(plugin_path, "/plugins/t.so");
+                assert_eq!(config_json, "{\"a\":1}");
+            }
+            ToWorker::Call { .. } => panic!("decoded init as a call"),
+        }
+    }
+
+    #[test]
+    fn result_round_trip() {
+        let (id, success, response) = round_trip_result(CResponse::new());
+        assert_eq!((id, success), (9, true));
+        assert_eq!(response.status, None);
+        assert!(response.headers.is_empty());
+        assert_eq!(response.body, None);
+
+        let (_, _, response) = round_trip_result(CResponse {
+            status: Some(202),
+            headers: vec![
+                ("x-tag".to_string(), "one".to_string()),
+                ("x-tag".to_string(), "two".to_string()),
+            ],
+            body: Some(vec![0, 0xff, 0]),
+        });
+        assert_eq!(response.status, Some(202));
+        assert_eq!(
+            response.headers,
+            vec![
+                ("x-tag".to_string(), "one".to_string()),
+                ("x-tag".to_string(), "two".to_string()),
+            ]
+        );
+        assert_eq!(response.body, Some(vec![0, 0xff, 0]));
+    }
+
+    #[test]
+    fn worker_messages_round_trip() {
+        assert!(matches!(
+            FromWorker::decode(&FromWorker::Ready.encode()).unwrap(),
+            FromWorker::Ready
+        ));
+        match FromWorker::decode(&FromWorker::InitFailed("no".to_string()).encode()).unwrap() {
+            FromWorker::InitFailed(msg) => assert_eq!(msg, "no"),
+            _ => panic!("decoded init failure as another message"),
+        }
+    }
+
+    #[test]
+    fn truncated_frames() {
+        let call = ToWorker::Call {
+            id: 1,
+            request: request(Some(c("CN=client"))),
+        }
+        .encode();
+        for len in 0..call.len() {
+            let err = ToWorker::decode(&call[..len]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let result = FromWorker::Result {
+            id: 1,
+            success: false,
+            response: CResponse {
+                status: Some(500),
+                headers: vec![("a".to_string(), "b".to_string())],
+                body: Some(b"body".to_vec()),
+            },
+        }
+        .encode();
+        for len in 0..result.len() {
+            let err = FromWorker::decode(&result[..len]).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn unknown_tags() {
+        for frame in [&[RESULT][..], &[0xff, 0, 0]] {
+            let err = ToWorker::decode(frame).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        for frame in [&[CALL][..], &[0xff]] {
+            let err = FromWorker::decode(frame).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"one").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+        let mut reader = &buf[..];
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+        // A stream cut off inside a frame is an error, not a clean close.
+        let mut reader = &buf[..5];
+        assert!(read_frame(&mut reader).is_err());
+
+        let mut reader = &(MAX_FRAME_LEN as u32 + 1).to_be_bytes()[..];
+        let err = read_frame(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
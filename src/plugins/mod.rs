@@ -1,6 +1,8 @@
-use std::io;
+use std::{io, sync::Arc};
 
-use missdemeanor::CRequest;
+use async_trait::async_trait;
+
+use missdemeanor::{CRequest, CResponse};
 
 use crate::config::Trigger;
 
@@ -13,10 +15,25 @@ pub use self::cabi::*;
 mod interpreted;
 pub use self::interpreted::*;
 
+mod ipc;
+
+mod process;
+pub use self::process::*;
+
+mod any;
+pub use self::any::*;
+
 pub trait NewPlugin: Sized {
     fn new(trigger: Trigger) -> Result<Self, io::Error>;
 }
 
+#[async_trait]
 pub trait Plugin {
-    fn run_trigger(&self, request: CRequest) -> Result<(), PluginError>;
+    /// Run the trigger for a request. The plugin may fill in `response`
+    /// whether it succeeds or fails.
+    async fn run_trigger(
+        &self,
+        request: Arc<CRequest>,
+        response: &mut CResponse,
+    ) -> Result<(), PluginError>;
 }
@@ -0,0 +1,529 @@
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    env,
+    fs::File,
+    hash::{Hash, Hasher},
+    io,
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    net::unix::{OwnedReadHalf, OwnedWriteHalf},
+    process::{Child, Command},
+    runtime::Handle,
+    sync::{self, oneshot},
+    time,
+};
+
+use missdemeanor::{CRequest, CResponse};
+
+use crate::{
+    config::Trigger,
+    plugins::{
+        cabi::Loaded,
+        err::PluginError,
+        ipc::{self, FromWorker, ToWorker},
+        NewPlugin, Plugin,
+    },
+};
+
+/// Set in the environment of a plugin worker process.
+pub const WORKER_ENV: &str = "MISS_DEMEANOR_PLUGIN_WORKER";
+
+/// How long a new worker may take to load its plugin and run `trigger_init`.
+const WORKER_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Reply = (Result<(), PluginError>, CResponse);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+static SPAWNER: Mutex<Option<mpsc::Sender<Job>>> = Mutex::new(None);
+
+/// Run `f` on the thread that starts workers and wait for its result. A
+/// worker is killed when the thread that started it exits, so workers are all
+/// started from this one long-lived thread rather than from the blocking
+/// pool, which retires idle threads.
+fn on_spawner<F, T>(f: F) -> Result<T, io::Error>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let spawner = {
+        let mut spawner = match SPAWNER.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        match *spawner {
+            Some(ref s) => s.clone(),
+            None => {
+                let (sender, receiver) = mpsc::channel::<Job>();
+                thread::Builder::new()
+                    .name("plugin-workers".to_string())
+                    .spawn(move || {
+                        for job in receiver {
+                            job();
+                        }
+                    })?;
+                *spawner = Some(sender.clone());
+                sender
+            }
+        }
+    };
+    let (sender, receiver) = mpsc::channel();
+    let exited = || io::Error::new(io::ErrorKind::BrokenPipe, "Plugin worker spawner exited");
+    spawner
+        .send(Box::new(move || {
+            let _ = sender.send(f());
+        }))
+        .map_err(|_| exited())?;
+    receiver.recv().map_err(|_| exited())
+}
+
+/// Calls waiting on a reply from the worker. Once the worker has exited,
+/// `alive` is false and the senders have been dropped.
+struct Calls {
+    pending: HashMap<u64, oneshot::Sender<Reply>>,
+    alive: bool,
+}
+
+/// A worker process that has loaded the plugin, and the server's end of the
+/// socketpair connected to it.
+struct Worker {
+    pid: libc::pid_t,
+    writer: sync::Mutex<OwnedWriteHalf>,
+    calls: Arc<Mutex<Calls>>,
+    next_id: AtomicU64,
+}
+
+fn lock(calls: &Mutex<Calls>) -> MutexGuard<'_, Calls> {
+    match calls.lock() {
+        Ok(c) => c,
+        Err(e) => e.into_inner(),
+    }
+}
+
+/// Send the plugin to load to a new worker and wait for it to be ready.
+fn handshake(
+    socket: &mut UnixStream,
+    name: &str,
+    plugin_path: &str,
+    config_json: &str,
+) -> Result<(), io::Error> {
+    let init = ToWorker::Init {
+        name: name.to_string(),
+        plugin_path: plugin_path.to_string(),
+        config_json: config_json.to_string(),
+    };
+    socket.set_read_timeout(Some(WORKER_START_TIMEOUT))?;
+    ipc::write_frame(socket, &init.encode())?;
+    let frame = match ipc::read_frame(socket) {
+        Ok(Some(f)) => f,
+        Ok(None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Trigger {}: plugin worker exited during startup", name),
+            ))
+        }
+        Err(ref e)
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Trigger {}: plugin worker did not start within {} seconds",
+                    name,
+                    WORKER_START_TIMEOUT.as_secs()
+                ),
+            ))
+        }
+        Err(e) => return Err(e),
+    };
+    socket.set_read_timeout(None)?;
+    match FromWorker::decode(&frame)? {
+        FromWorker::Ready => Ok(()),
+        FromWorker::InitFailed(msg) => Err(io::Error::new(io::ErrorKind::InvalidInput, msg)),
+        FromWorker::Result { .. } => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected result from plugin worker",
+        )),
+    }
+}
+
+impl Worker {
+    /// Start a worker and wait for it to load the plugin. This blocks for as
+    /// long as the plugin's `trigger_init` runs, up to `WORKER_START_TIMEOUT`.
+    fn spawn(name: &str, plugin_path: &str, config_json: &str) -> Result<Self, io::Error> {
+        let (mut socket, worker_socket) = UnixStream::pair()?;
+        // The worker gets its own process group so a Ctrl-C aimed at the
+        // server does not kill it before in-flight requests have drained.
+        let mut command = Command::new(env::current_exe()?);
+        command
+            .env(WORKER_ENV, name)
+            .stdin(Stdio::from(OwnedFd::from(worker_socket)))
+            .process_group(0);
+        let runtime = Handle::current();
+        let mut child = on_spawner(move || {
+            let _runtime = runtime.enter();
+            command.spawn()
+        })
+        .and_then(|r| r)?;
+        let pid = child.id().unwrap_or(0) as libc::pid_t;
+        if let Err(e) = handshake(&mut socket, name, plugin_path, config_json) {
+            let _ = child.start_kill();
+            return Err(e);
+        }
+
+        socket.set_nonblocking(true)?;
+        let (reader, writer) = tokio::net::UnixStream::from_std(socket)?.into_split();
+        let calls = Arc::new(Mutex::new(Calls {
+            pending: HashMap::new(),
+            alive: true,
+        }));
+        tokio::spawn(supervise(
+            name.to_string(),
+            reader,
+            Arc::clone(&calls),
+            child,
+        ));
+        Ok(Worker {
+            pid,
+            writer: sync::Mutex::new(writer),
+            calls,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        lock(&self.calls).alive
+    }
+
+    /// Send a request to the worker. The receiver is closed without a reply
+    /// if the worker exits first.
+    async fn call(&self, request: &CRequest) -> Result<oneshot::Receiver<Reply>, io::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut calls = lock(&self.calls);
+            if !calls.alive {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Plugin worker has exited",
+                ));
+            }
+            calls.pending.insert(id, sender);
+        }
+        let frame = ToWorker::Call {
+            id,
+            request: clone_request(request),
+        }
+        .encode();
+        let mut writer = self.writer.lock().await;
+        if let Err(e) = ipc::write_frame_async(&mut *writer, &frame).await {
+            lock(&self.calls).pending.remove(&id);
+            return Err(e);
+        }
+        Ok(receiver)
+    }
+
+    /// Kill the worker, failing every call it is running, so the next request
+    /// starts a new one.
+    fn kill(&self) {
+        // The check keeps a reaped worker's pid, which may have been reused,
+        // from being signalled.
+        let mut calls = lock(&self.calls);
+        if calls.alive {
+            unsafe { libc::kill(self.pid, libc::SIGKILL) };
+            calls.alive = false;
+            calls.pending.clear();
+        }
+    }
+}
+
+fn clone_request(request: &CRequest) -> CRequest {
+    CRequest {
+        method: request.method.clone(),
+        uri: request.uri.clone(),
+        path: request.path.clone(),
+        query: request.query.clone(),
+        path_params: request.path_params.clone(),
+        query_params: request.query_params.clone(),
+        headers: request.headers.clone(),
+        body: request.body.as_bytes().to_vec().into(),
+        client_subject: request.client_subject.clone(),
+        client_sans: request.client_sans.clone(),
+    }
+}
+
+/// Hand replies from the worker to the calls waiting on them until it exits,
+/// then fail any that are left and reap it.
+async fn supervise(
+    name: String,
+    mut reader: OwnedReadHalf,
+    calls: Arc<Mutex<Calls>>,
+    mut child: Child,
+) {
+    loop {
+        let frame = match ipc::read_frame_async(&mut reader).await {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            Err(e) => {
+                error!(
+                    "Failed to read from plugin worker for trigger {}: {}",
+                    name, e
+                );
+                break;
+            }
+        };
+        match FromWorker::decode(&frame) {
+            Ok(FromWorker::Result {
+                id,
+                success,
+                response,
+            }) => {
+                let result = if success {
+                    Ok(())
+                } else {
+                    Err(PluginError::new(500, "Internal server error"))
+                };
+                if let Some(sender) = lock(&calls).pending.remove(&id) {
+                    let _ = sender.send((result, response));
+                }
+            }
+            Ok(_) => {
+                error!("Unexpected message from plugin worker for trigger {}", name);
+                break;
+            }
+            Err(e) => {
+                error!(
+                    "Invalid message from plugin worker for trigger {}: {}",
+                    name, e
+                );
+                break;
+            }
+        }
+    }
+
+    {
+        let mut calls = lock(&calls);
+        calls.alive = false;
+        calls.pending.clear();
+    }
+    // Stop a worker that is still running after a protocol error.
+    let _ = child.start_kill();
+    match child.wait().await {
+        Ok(status) if status.success() => info!("Plugin worker for trigger {} exited", name),
+        Ok(status) => error!("Plugin worker for trigger {} died: {}", name, status),
+        Err(e) => error!("Failed to reap plugin worker for trigger {}: {}", name, e),
+    }
+}
+
+/// A C ABI plugin loaded into a supervised worker process, so a crash in the
+/// plugin only fails the requests it was running. A worker that has died is
+/// replaced for the next request.
+pub struct ProcessPlugin {
+    worker: sync::Mutex<Arc<Worker>>,
+    config_json: String,
+    pub config: Trigger,
+}
+
+impl NewPlugin for ProcessPlugin {
+    fn new(config: Trigger) -> Result<Self, io::Error> {
+        let config_json = config.config_json();
+        let worker = Worker::spawn(&config.name, &config.plugin_path, &config_json)?;
+        Ok(ProcessPlugin {
+            worker: sync::Mutex::new(Arc::new(worker)),
+            config_json,
+            config,
+        })
+    }
+}
+
+impl ProcessPlugin {
+    /// The running worker, restarting it first if it has died.
+    async fn worker(&self) -> Result<Arc<Worker>, PluginError> {
+        let mut worker = self.worker.lock().await;
+        if !worker.is_alive() {
+            info!("Restarting plugin worker for trigger {}", self.config.name);
+            let name = self.config.name.clone();
+            let plugin_path = self.config.plugin_path.clone();
+            let config_json = self.config_json.clone();
+            let restarted = tokio::task::spawn_blocking(move || {
+                Worker::spawn(&name, &plugin_path, &config_json)
+            })
+            .await
+            .map_err(io::Error::from)
+            .and_then(|r| r)
+            .map_err(|e| {
+                error!(
+                    "Failed to restart plugin worker for trigger {}: {}",
+                    self.config.name, e
+                );
+                PluginError::crashed("Trigger plugin unavailable")
+            })?;
+            *worker = Arc::new(restarted);
+        }
+        Ok(Arc::clone(&worker))
+    }
+}
+
+#[async_trait]
+impl Plugin for ProcessPlugin {
+    async fn run_trigger(
+        &self,
+        request: Arc<CRequest>,
+        response: &mut CResponse,
+    ) -> Result<(), PluginError> {
+        let worker = self.worker().await?;
+        let receiver = worker.call(&request).await.map_err(|e| {
+            error!(
+                "Failed to send request to plugin worker for trigger {}: {}",
+                self.config.name, e
+            );
+            PluginError::crashed("Trigger plugin crashed")
+        })?;
+        let reply = match self.config.timeout {
+            // Unlike a thread, a worker can be stopped, so a call that times
+            // out is killed along with anything else the worker is running.
+            Some(timeout) => match time::timeout(Duration::from_secs(timeout), receiver).await {
+                Ok(r) => r,
+                Err(_) => {
+                    error!(
+                        "Trigger plugin {} timed out after {} seconds; killing its worker",
+                        self.config.name, timeout
+                    );
+                    worker.kill();
+                    return Err(PluginError::timeout("Trigger timed out"));
+                }
+            },
+            None => receiver.await,
+        };
+        let (result, plugin_response) = reply.map_err(|_| {
+            error!(
+                "Plugin worker for trigger {} died while handling a request",
+                self.config.name
+            );
+            PluginError::crashed("Trigger plugin crashed")
+        })?;
+        *response = plugin_response;
+        result
+    }
+}
+
+impl Hash for ProcessPlugin {
+    fn hash<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        self.config.hash(hasher)
+    }
+}
+
+impl PartialEq for ProcessPlugin {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.config == rhs.config
+    }
+}
+
+impl Eq for ProcessPlugin {}
+
+impl Borrow<String> for ProcessPlugin {
+    fn borrow(&self) -> &String {
+        self.config.borrow()
+    }
+}
+
+fn send(writer: &Mutex<UnixStream>, message: FromWorker) -> Result<(), io::Error> {
+    let mut socket = match writer.lock() {
+        Ok(s) => s,
+        Err(e) => e.into_inner(),
+    };
+    ipc::write_frame(&mut *socket, &message.encode())
+}
+
+/// Entry point of a plugin worker process. The socketpair to the server is
+/// passed as stdin; the worker loads the plugin it is sent, then runs each
+/// request on its own thread until the server closes the socket.
+pub fn run_worker() -> Result<(), io::Error> {
+    // A worker stuck in the plugin never sees the socket close, so also tie
+    // its lifetime to the server's. This fires when the thread that started
+    // the worker exits, which on_spawner keeps alive as long as the server.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        if libc::getppid() == 1 {
+            return Ok(());
+        }
+    }
+    let socket = UnixStream::from(io::stdin().as_fd().try_clone_to_owned()?);
+    // Keep plugins that read stdin from consuming the protocol.
+    let null = File::open("/dev/null")?;
+    if unsafe { libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    env::remove_var(WORKER_ENV);
+
+    let mut reader = socket.try_clone()?;
+    let writer = Arc::new(Mutex::new(socket));
+
+    let loaded = match ipc::read_frame(&mut reader)? {
+        Some(frame) => match ToWorker::decode(&frame)? {
+            ToWorker::Init {
+                name,
+                plugin_path,
+                config_json,
+            } => match Loaded::load(&name, &plugin_path, &config_json) {
+                Ok(l) => {
+                    send(&writer, FromWorker::Ready)?;
+                    Arc::new(l)
+                }
+                Err(e) => return send(&writer, FromWorker::InitFailed(e.to_string())),
+            },
+            ToWorker::Call { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Plugin worker called before it was initialized",
+                ))
+            }
+        },
+        None => return Ok(()),
+    };
+
+    while let Some(frame) = ipc::read_frame(&mut reader)? {
+        let (id, request) = match ToWorker::decode(&frame)? {
+            ToWorker::Call { id, request } => (id, request),
+            ToWorker::Init { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Plugin worker initialized twice",
+                ))
+            }
+        };
+        let loaded = Arc::clone(&loaded);
+        let writer = Arc::clone(&writer);
+        thread::spawn(move || {
+            let mut response = CResponse::new();
+            let success = loaded.call(&request, &mut response).is_ok();
+            let result = FromWorker::Result {
+                id,
+                success,
+                response,
+            };
+            if let Err(e) = send(&writer, result) {
+                error!("Failed to send plugin result to server: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
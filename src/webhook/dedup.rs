@@ -0,0 +1,379 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{header::HeaderName, HeaderMap, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Dedup, err::DemeanorError};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(s) => s,
+        Err(e) => e.into_inner(),
+    }
+}
+
+/// Delivery keys seen within the TTL, and the keys of deliveries whose
+/// triggers are still running. `order` lists the seen keys by expiry so the
+/// oldest can be expired or evicted first.
+#[derive(Default)]
+struct Seen {
+    expiries: HashMap<String, u64>,
+    order: VecDeque<(u64, String)>,
+    in_flight: HashSet<String>,
+}
+
+impl Seen {
+    fn pop_oldest(&mut self) {
+        if let Some((_, key)) = self.order.pop_front() {
+            self.expiries.remove(&key);
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        while self.order.front().is_some_and(|&(e, _)| e <= now) {
+            self.pop_oldest();
+        }
+    }
+
+    fn insert(&mut self, key: String, expiry: u64) {
+        if !self.expiries.contains_key(&key) {
+            self.expiries.insert(key.clone(), expiry);
+            self.order.push_back((expiry, key));
+        }
+    }
+
+    /// The live keys in the format of the persistence file.
+    fn contents(&self) -> String {
+        self.order
+            .iter()
+            .map(|(expiry, key)| format!("{} {}\n", expiry, key))
+            .collect()
+    }
+}
+
+/// Whether the persistence file is behind the cache, and whether the cache
+/// has been dropped.
+#[derive(Default)]
+struct Flush {
+    dirty: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    seen: Mutex<Seen>,
+    flush: Mutex<Flush>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn mark_dirty(&self) {
+        lock(&self.flush).dirty = true;
+        self.wake.notify_one();
+    }
+}
+
+/// Rewrite the persistence file whenever the cache changes. Changes made
+/// while a write is in progress are picked up by the next one, so a burst of
+/// deliveries costs a single write. Runs on its own thread so that requests
+/// never wait on the file system.
+fn write_changes(shared: Arc<Shared>, path: PathBuf) {
+    loop {
+        let closed = {
+            let mut flush = lock(&shared.flush);
+            while !flush.dirty && !flush.closed {
+                flush = shared.wake.wait(flush).unwrap_or_else(|e| e.into_inner());
+            }
+            if !flush.dirty {
+                return;
+            }
+            flush.dirty = false;
+            flush.closed
+        };
+        let contents = lock(&shared.seen).contents();
+        if let Err(e) = write_atomically(&path, contents) {
+            error!("Failed to persist dedup cache {}: {}", path.display(), e);
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+fn write_atomically(path: &Path, contents: String) -> Result<(), io::Error> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// The state of a delivery when a request for it arrives.
+pub(crate) enum Delivery<'a> {
+    /// Not seen before; the triggers should run.
+    New(InFlight<'a>),
+    /// Already handled within the TTL.
+    Duplicate,
+    /// Another request is still running the triggers for it.
+    InProgress,
+}
+
+/// A delivery whose triggers are running. It is only remembered once
+/// `commit` is called, so dropping it after the triggers failed, or after the
+/// client went away, lets a redelivery run them again.
+pub(crate) struct InFlight<'a> {
+    cache: &'a DedupCache,
+    key: String,
+}
+
+impl InFlight<'_> {
+    /// Remember the delivery after its triggers succeeded.
+    pub fn commit(self) {
+        let cache = self.cache;
+        let now = now();
+        let mut seen = lock(&cache.shared.seen);
+        seen.in_flight.remove(&self.key);
+        seen.expire(now);
+        while seen.expiries.len() >= cache.capacity {
+            seen.pop_oldest();
+        }
+        seen.insert(self.key.clone(), now + cache.ttl);
+        drop(seen);
+        cache.shared.mark_dirty();
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        lock(&self.cache.shared.seen).in_flight.remove(&self.key);
+    }
+}
+
+/// Bounded cache of recently seen deliveries for one endpoint.
+pub(crate) struct DedupCache {
+    header: Option<HeaderName>,
+    ttl: u64,
+    capacity: usize,
+    status: StatusCode,
+    shared: Arc<Shared>,
+}
+
+impl DedupCache {
+    pub fn new(config: &Dedup) -> Result<Self, DemeanorError> {
+        let header = config
+            .header
+            .as_deref()
+            .map(|h| {
+                HeaderName::from_bytes(h.as_bytes())
+                    .map_err(|_| DemeanorError::new(format!("Invalid dedup header {}", h)))
+            })
+            .transpose()?;
+        if config.capacity == 0 {
+            return Err(DemeanorError::new("Dedup capacity must be greater than 0"));
+        }
+        let status = StatusCode::from_u16(config.status)
+            .map_err(|_| DemeanorError::new(format!("Invalid dedup status {}", config.status)))?;
+
+        let cache = DedupCache {
+            header,
+            ttl: config.ttl,
+            capacity: config.capacity,
+            status,
+            shared: Arc::new(Shared::default()),
+        };
+        if let Some(ref path) = config.persist_path {
+            let path = PathBuf::from(path);
+            cache.restore(&path).map_err(|e| {
+                DemeanorError::new(format!(
+                    "Failed to load dedup cache {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            let shared = Arc::clone(&cache.shared);
+            thread::Builder::new()
+                .name("dedup-persist".to_string())
+                .spawn(move || write_changes(shared, path))
+                .map_err(|e| {
+                    DemeanorError::new(format!("Failed to start dedup cache writer: {}", e))
+                })?;
+        }
+        Ok(cache)
+    }
+
+    /// Status returned for duplicate deliveries.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The delivery key: the configured header if the request carries it,
+    /// otherwise a SHA-256 hash of the body.
+    pub fn key(&self, headers: &HeaderMap, body: &[u8]) -> String {
+        match self
+            .header
+            .as_ref()
+            .and_then(|h| headers.get(h))
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(v) => format!("header:{}", v),
+            None => format!("sha256:{}", hex::encode(Sha256::digest(body))),
+        }
+    }
+
+    /// Start processing a delivery unless it was already handled within the
+    /// TTL or is being handled by another request.
+    pub fn begin(&self, key: &str) -> Delivery<'_> {
+        let mut seen = lock(&self.shared.seen);
+        seen.expire(now());
+        if seen.expiries.contains_key(key) {
+            Delivery::Duplicate
+        } else if !seen.in_flight.insert(key.to_string()) {
+            Delivery::InProgress
+        } else {
+            Delivery::New(InFlight {
+                cache: self,
+                key: key.to_string(),
+            })
+        }
+    }
+
+    /// Load unexpired keys saved by a previous instance.
+    fn restore(&self, path: &Path) -> Result<(), io::Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let now = now();
+        let mut entries = contents
+            .lines()
+            .filter_map(|line| {
+                let (expiry, key) = line.split_once(' ')?;
+                Some((expiry.parse::<u64>().ok()?, key.to_string()))
+            })
+            .filter(|&(expiry, _)| expiry > now)
+            .collect::<Vec<_>>();
+        entries.sort();
+        let skip = entries.len().saturating_sub(self.capacity);
+        let mut seen = lock(&self.shared.seen);
+        for (expiry, key) in entries.into_iter().skip(skip) {
+            seen.insert(key, expiry);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DedupCache {
+    /// Let the writer thread save any outstanding changes and exit.
+    fn drop(&mut self) {
+        lock(&self.shared.flush).closed = true;
+        self.shared.wake.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process, time::Duration};
+
+    use super::*;
+
+    fn cache(path: &Path) -> DedupCache {
+        let config = toml::from_str::<Dedup>(&format!(
+            "capacity = 2\npersist_path = \"{}\"",
+            path.display()
+        ))
+        .unwrap();
+        DedupCache::new(&config).unwrap()
+    }
+
+    fn wait_for(path: &Path, keys: &[&str]) {
+        for _ in 0..100 {
+            let written = fs::read_to_string(path)
+                .map(|c| {
+                    c.lines()
+                        .filter_map(|l| l.split_once(' ').map(|(_, k)| k.to_string()))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            if written == keys {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("{} was never written", path.display());
+    }
+
+    fn run(cache: &DedupCache, key: &str, success: bool) -> &'static str {
+        match cache.begin(key) {
+            Delivery::New(in_flight) => {
+                if success {
+                    in_flight.commit();
+                }
+                "new"
+            }
+            Delivery::Duplicate => "duplicate",
+            Delivery::InProgress => "in progress",
+        }
+    }
+
+    #[test]
+    fn deliveries_are_remembered_once_they_succeed() {
+        let config = toml::from_str::<Dedup>("capacity = 2").unwrap();
+        let cache = DedupCache::new(&config).unwrap();
+
+        let first = match cache.begin("a") {
+            Delivery::New(in_flight) => in_flight,
+            _ => panic!("new delivery was not accepted"),
+        };
+        assert_eq!(run(&cache, "a", true), "in progress");
+        drop(first);
+        assert_eq!(run(&cache, "a", false), "new");
+        assert_eq!(run(&cache, "a", true), "new");
+        assert_eq!(run(&cache, "a", true), "duplicate");
+
+        // Eviction drops the oldest delivery once capacity is reached.
+        assert_eq!(run(&cache, "b", true), "new");
+        assert_eq!(run(&cache, "c", true), "new");
+        assert_eq!(run(&cache, "a", false), "new");
+        assert_eq!(run(&cache, "b", true), "duplicate");
+        let seen = lock(&cache.shared.seen);
+        assert_eq!(seen.order.len(), 2);
+        assert!(seen.in_flight.is_empty());
+    }
+
+    #[test]
+    fn persisted_keys_survive_a_restart() {
+        let path = env::temp_dir().join(format!("miss-demeanor-dedup-test-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let first = cache(&path);
+        assert_eq!(run(&first, "a", true), "new");
+        assert_eq!(run(&first, "b", false), "new");
+        assert_eq!(run(&first, "c", true), "new");
+        assert_eq!(run(&first, "d", true), "new");
+        drop(first);
+        wait_for(&path, &["c", "d"]);
+
+        // "a" was evicted to make room for "d", and "b" failed.
+        let second = cache(&path);
+        assert_eq!(run(&second, "c", true), "duplicate");
+        assert_eq!(run(&second, "d", true), "duplicate");
+        assert_eq!(run(&second, "a", true), "new");
+        assert_eq!(run(&second, "b", true), "new");
+        drop(second);
+        let _ = fs::remove_file(&path);
+    }
+}
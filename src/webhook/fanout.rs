@@ -0,0 +1,288 @@
+use std::{borrow::Borrow, collections::HashSet, hash::Hash, sync::Arc};
+
+use futures::future;
+use http_body_util::Full;
+use hyper::{body::Bytes, header, Response, StatusCode};
+
+use missdemeanor::{CRequest, CResponse};
+
+use crate::{
+    config::{Aggregation, Endpoint, Execution},
+    plugins::{Plugin, PluginError, PluginErrorKind},
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Success,
+    Failure,
+    Timeout,
+    Crashed,
+    Skipped,
+}
+
+#[derive(Serialize)]
+struct TriggerOutcome {
+    name: String,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FanOutReport {
+    success: bool,
+    triggers: Vec<TriggerOutcome>,
+}
+
+async fn run_one<P>(
+    plugins: &HashSet<P>,
+    name: &String,
+    request: Arc<CRequest>,
+) -> Result<(), PluginError>
+where
+    P: Hash + Eq + Borrow<String> + Plugin,
+{
+    let trigger = plugins.get(name).ok_or_else(|| {
+        error!("Trigger plugin {} not found", name);
+        PluginError::new(500, "Plugin not found")
+    })?;
+    let mut response = CResponse::new();
+    trigger
+        .run_trigger(request, &mut response)
+        .await
+        .map_err(|e| {
+            error!("Trigger plugin {} failed with error: {}", name, e);
+            // Report the plugin's own explanation if it gave one, without
+            // losing whether it timed out or crashed.
+            match response.body {
+                Some(ref body) if e.kind() == PluginErrorKind::Failure => PluginError::new(
+                    response.status.unwrap_or(500),
+                    String::from_utf8_lossy(body),
+                ),
+                Some(ref body) => e.with_message(String::from_utf8_lossy(body)),
+                None => e,
+            }
+        })
+}
+
+fn outcome(name: &str, result: Option<Result<(), PluginError>>) -> TriggerOutcome {
+    let (status, error) = match result {
+        Some(Ok(())) => (Status::Success, None),
+        Some(Err(e)) if e.kind() == PluginErrorKind::Timeout => {
+            (Status::Timeout, Some(e.message().to_string()))
+        }
+        Some(Err(e)) if e.kind() == PluginErrorKind::Crashed => {
+            (Status::Crashed, Some(e.message().to_string()))
+        }
+        Some(Err(e)) => (Status::Failure, Some(e.message().to_string())),
+        None => (Status::Skipped, None),
+    };
+    TriggerOutcome {
+        name: name.to_string(),
+        status,
+        error,
+    }
+}
+
+/// Status of a failed fan-out: 500 if any trigger failed outright, otherwise
+/// 504 if one timed out or 502 if one crashed.
+fn failure_status(results: &[Option<Result<(), PluginError>>]) -> StatusCode {
+    let kinds = results
+        .iter()
+        .filter_map(|r| match *r {
+            Some(Err(ref e)) => Some(e.kind()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if kinds.contains(&PluginErrorKind::Failure) {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else if kinds.contains(&PluginErrorKind::Timeout) {
+        StatusCode::GATEWAY_TIMEOUT
+    } else if kinds.contains(&PluginErrorKind::Crashed) {
+        StatusCode::BAD_GATEWAY
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Run every trigger of an endpoint and report the individual outcomes as
+/// JSON. Concurrent triggers all run at once; sequential triggers run in
+/// configuration order and stop early once the aggregation policy has decided
+/// the result.
+pub(crate) async fn run_triggers<P>(
+    endpoint: &Endpoint,
+    plugins: Arc<HashSet<P>>,
+    request: Arc<CRequest>,
+) -> Response<Full<Bytes>>
+where
+    P: 'static + Hash + Eq + Borrow<String> + Plugin + Send + Sync,
+{
+    let names = endpoint.triggers().cloned().collect::<Vec<_>>();
+    let results = match endpoint.execution {
+        Execution::Sequential => {
+            let mut results = Vec::new();
+            let mut decided = false;
+            for name in names.iter() {
+                if decided {
+                    results.push(None);
+                    continue;
+                }
+                let result = run_one(&plugins, name, Arc::clone(&request)).await;
+                decided = match endpoint.aggregation {
+                    Aggregation::Any => result.is_ok(),
+                    Aggregation::FirstFailure => result.is_err(),
+                    _ => false,
+                };
+                results.push(Some(result));
+            }
+            results
+        }
+        _ => future::join_all(
+            names
+                .iter()
+                .map(|name| run_one(&plugins, name, Arc::clone(&request))),
+        )
+        .await
+        .into_iter()
+        .map(Some)
+        .collect(),
+    };
+
+    let success = match endpoint.aggregation {
+        Aggregation::Any => results.iter().any(|r| matches!(r, Some(Ok(())))),
+        _ => results.iter().all(|r| matches!(r, Some(Ok(())))),
+    };
+    let status = if success {
+        StatusCode::OK
+    } else {
+        failure_status(&results)
+    };
+    let report = FanOutReport {
+        success,
+        triggers: names
+            .iter()
+            .zip(results)
+            .map(|(name, result)| outcome(name, result))
+            .collect(),
+    };
+
+    let body = match serde_json::to_vec(&report) {
+        Ok(b) => b,
+        Err(e) => {
+            error!("{}", e);
+            return PluginError::new(500, "Failed to serialize trigger outcomes").into_response();
+        }
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ffi::CString;
+
+    use async_trait::async_trait;
+    use http_body_util::BodyExt;
+    use missdemeanor::Body;
+
+    /// Succeeds, fails, times out or crashes depending on its name, setting
+    /// a response body as it goes.
+    #[derive(PartialEq, Eq, Hash)]
+    struct Scripted(String);
+
+    #[async_trait]
+    impl Plugin for Scripted {
+        async fn run_trigger(
+            &self,
+            _: Arc<CRequest>,
+            response: &mut CResponse,
+        ) -> Result<(), PluginError> {
+            response.body = Some(format!("{} explained", self.0).into_bytes());
+            match self.0.as_str() {
+                "ok" => Ok(()),
+                "fail" => Err(PluginError::new(500, "Internal server error")),
+                "slow" => Err(PluginError::timeout("Trigger timed out")),
+                _ => Err(PluginError::crashed("Trigger plugin crashed")),
+            }
+        }
+    }
+
+    impl Borrow<String> for Scripted {
+        fn borrow(&self) -> &String {
+            &self.0
+        }
+    }
+
+    fn request() -> Arc<CRequest> {
+        let empty = || CString::new("").unwrap();
+        Arc::new(CRequest {
+            method: CString::new("POST").unwrap(),
+            uri: CString::new("/x").unwrap(),
+            path: CString::new("/x").unwrap(),
+            query: empty(),
+            path_params: Vec::new(),
+            query_params: Vec::new(),
+            headers: Vec::new(),
+            body: Body::new(Vec::new()),
+            client_subject: None,
+            client_sans: Vec::new(),
+        })
+    }
+
+    async fn run(names: &[&str], aggregation: &str) -> (StatusCode, serde_json::Value) {
+        let endpoint = toml::from_str::<Endpoint>(&format!(
+            "path = \"/x\"\ntrigger_names = {:?}\naggregation = \"{}\"",
+            names, aggregation
+        ))
+        .unwrap();
+        let plugins = names.iter().map(|n| Scripted(n.to_string())).collect();
+        let response = run_triggers(&endpoint, Arc::new(plugins), request()).await;
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn outcomes_keep_their_kind() {
+        let (status, report) = run(&["ok", "fail", "slow", "crash"], "all").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let outcomes = report["triggers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| (t["status"].as_str().unwrap(), t["error"].as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("success", None),
+                ("failure", Some("fail explained")),
+                ("timeout", Some("slow explained")),
+                ("crashed", Some("crash explained")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn aggregate_status() {
+        assert_eq!(
+            run(&["ok", "slow"], "all").await.0,
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            run(&["ok", "crash"], "all").await.0,
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            run(&["slow", "crash"], "any").await.0,
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(run(&["slow", "ok"], "any").await.0, StatusCode::OK);
+    }
+}
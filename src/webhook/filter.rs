@@ -0,0 +1,290 @@
+use hyper::{header::HeaderName, HeaderMap};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{config::Filter, err::DemeanorError};
+
+enum Source {
+    Header(HeaderName),
+    /// JSON pointer into the request body.
+    Field(String),
+}
+
+enum Condition {
+    Equals(String),
+    Regex(Regex),
+    Exists(bool),
+    OneOf(Vec<String>),
+}
+
+impl Condition {
+    fn matches(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (Condition::Exists(exists), v) => *exists == v.is_some(),
+            (_, None) => false,
+            (Condition::Equals(e), Some(v)) => e == v,
+            (Condition::Regex(r), Some(v)) => r.is_match(v),
+            (Condition::OneOf(o), Some(v)) => o.iter().any(|e| e == v),
+        }
+    }
+}
+
+/// A compiled `[[server.endpoints.filters]]` entry.
+pub(crate) struct RequestFilter {
+    source: Source,
+    condition: Condition,
+}
+
+impl RequestFilter {
+    pub fn new(config: &Filter) -> Result<Self, DemeanorError> {
+        let source = match (&config.header, &config.field) {
+            (Some(h), None) => Source::Header(
+                HeaderName::from_bytes(h.as_bytes())
+                    .map_err(|_| DemeanorError::new(format!("Invalid filter header {}", h)))?,
+            ),
+            (None, Some(f)) => {
+                if !f.is_empty() && !f.starts_with('/') {
+                    return Err(DemeanorError::new(format!(
+                        "Filter field {} must be a JSON pointer starting with /",
+                        f
+                    )));
+                }
+                Source::Field(f.clone())
+            }
+            (_, _) => {
+                return Err(DemeanorError::new(
+                    "Exactly one of header and field must be set on a filter",
+                ));
+            }
+        };
+        let condition = match (&config.equals, &config.regex, config.exists, &config.one_of) {
+            (Some(e), None, None, None) => Condition::Equals(e.clone()),
+            (None, Some(r), None, None) => Condition::Regex(
+                Regex::new(r)
+                    .map_err(|e| DemeanorError::new(format!("Invalid filter regex: {}", e)))?,
+            ),
+            (None, None, Some(e), None) => Condition::Exists(e),
+            (None, None, None, Some(o)) => Condition::OneOf(o.clone()),
+            (_, _, _, _) => {
+                return Err(DemeanorError::new(
+                    "Exactly one of equals, regex, exists and one_of must be set on a filter",
+                ));
+            }
+        };
+        Ok(RequestFilter { source, condition })
+    }
+
+    fn needs_body(&self) -> bool {
+        matches!(self.source, Source::Field(_))
+    }
+
+    fn matches(&self, headers: &HeaderMap, body: Option<&Value>) -> bool {
+        match self.source {
+            // A header sent several times matches if any of its values does.
+            Source::Header(ref name) => {
+                if let Condition::Exists(exists) = self.condition {
+                    return exists == headers.contains_key(name);
+                }
+                headers
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .any(|v| self.condition.matches(Some(v)))
+            }
+            Source::Field(ref pointer) => {
+                let value = body.and_then(|b| b.pointer(pointer)).map(|v| match v {
+                    Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                });
+                self.condition.matches(value.as_deref())
+            }
+        }
+    }
+}
+
+/// Whether a request meets every filter. The body is only parsed if a filter
+/// looks at it; a body that is not JSON has no fields.
+pub(crate) fn matches_all(filters: &[RequestFilter], headers: &HeaderMap, body: &[u8]) -> bool {
+    let json = if filters.iter().any(RequestFilter::needs_body) {
+        serde_json::from_slice::<Value>(body).ok()
+    } else {
+        None
+    };
+    filters.iter().all(|f| f.matches(headers, json.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn filter(config: &str) -> RequestFilter {
+        RequestFilter::new(&toml::from_str::<Filter>(config).unwrap()).unwrap()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn matches(config: &str, header_pairs: &[(&str, &str)], body: &str) -> bool {
+        matches_all(&[filter(config)], &headers(header_pairs), body.as_bytes())
+    }
+
+    const BODY: &str = r#"{"action": "closed", "number": 3, "pull_request": {"merged": true, "labels": [{"name": "deploy"}]}, "note": null}"#;
+
+    #[test]
+    fn header_equals() {
+        let config = r#"header = "X-GitHub-Event"
+equals = "push""#;
+        assert!(matches(config, &[("x-github-event", "push")], ""));
+        assert!(!matches(config, &[("x-github-event", "Push")], ""));
+        assert!(!matches(config, &[], ""));
+        // Any value of a repeated header can match.
+        assert!(matches(
+            config,
+            &[("x-github-event", "ping"), ("x-github-event", "push")],
+            ""
+        ));
+    }
+
+    #[test]
+    fn header_regex() {
+        let config = r#"header = "x-gitlab-event"
+regex = "^(Push|Tag Push) Hook$""#;
+        assert!(matches(config, &[("x-gitlab-event", "Tag Push Hook")], ""));
+        assert!(!matches(
+            config,
+            &[("x-gitlab-event", "Merge Request Hook")],
+            ""
+        ));
+
+        // Unanchored expressions match anywhere in the value.
+        let config = r#"header = "x-gitlab-event"
+regex = "Push""#;
+        assert!(matches(config, &[("x-gitlab-event", "Tag Push Hook")], ""));
+    }
+
+    #[test]
+    fn header_exists() {
+        let present = r#"header = "x-hub-signature-256"
+exists = true"#;
+        let absent = r#"header = "x-hub-signature-256"
+exists = false"#;
+        assert!(matches(present, &[("x-hub-signature-256", "")], ""));
+        assert!(!matches(present, &[("x-other", "a")], ""));
+        assert!(matches(absent, &[("x-other", "a")], ""));
+        assert!(!matches(absent, &[("x-hub-signature-256", "a")], ""));
+    }
+
+    #[test]
+    fn header_one_of() {
+        let config = r#"header = "x-github-event"
+one_of = ["push", "pull_request"]"#;
+        assert!(matches(config, &[("x-github-event", "pull_request")], ""));
+        assert!(!matches(config, &[("x-github-event", "issues")], ""));
+        assert!(!matches(config, &[], ""));
+    }
+
+    #[test]
+    fn field_lookup() {
+        assert!(matches(
+            r#"field = "/action"
+equals = "closed""#,
+            &[],
+            BODY
+        ));
+        // Values that are not strings are compared as JSON.
+        assert!(matches(
+            r#"field = "/number"
+equals = "3""#,
+            &[],
+            BODY
+        ));
+        assert!(matches(
+            r#"field = "/pull_request/merged"
+one_of = ["true"]"#,
+            &[],
+            BODY
+        ));
+        assert!(matches(
+            r#"field = "/pull_request/labels/0/name"
+regex = "^dep""#,
+            &[],
+            BODY
+        ));
+        assert!(matches(
+            r#"field = "/note"
+exists = true"#,
+            &[],
+            BODY
+        ));
+    }
+
+    #[test]
+    fn missing_fields() {
+        let equals = r#"field = "/pull_request/labels/1/name"
+equals = "deploy""#;
+        let absent = r#"field = "/pull_request/labels/1/name"
+exists = false"#;
+        assert!(!matches(equals, &[], BODY));
+        assert!(matches(absent, &[], BODY));
+    }
+
+    #[test]
+    fn body_that_is_not_json() {
+        let exists = r#"field = "/action"
+exists = true"#;
+        let absent = r#"field = "/action"
+exists = false"#;
+        assert!(!matches(exists, &[], "action=closed"));
+        assert!(matches(absent, &[], "action=closed"));
+        assert!(!matches(exists, &[], ""));
+    }
+
+    #[test]
+    fn every_filter_must_match() {
+        let filters = [
+            filter(
+                r#"header = "x-github-event"
+equals = "pull_request""#,
+            ),
+            filter(
+                r#"field = "/pull_request/merged"
+equals = "true""#,
+            ),
+        ];
+        let event = headers(&[("x-github-event", "pull_request")]);
+        assert!(matches_all(&filters, &event, BODY.as_bytes()));
+        assert!(!matches_all(
+            &filters,
+            &headers(&[("x-github-event", "push")]),
+            BODY.as_bytes()
+        ));
+        assert!(!matches_all(&filters, &event, b"{}"));
+        assert!(matches_all(&[], &HeaderMap::new(), b""));
+    }
+
+    #[test]
+    fn invalid_filters() {
+        for config in [
+            "equals = \"a\"",
+            "header = \"a\"\nfield = \"/a\"\nequals = \"a\"",
+            "field = \"a\"\nequals = \"a\"",
+            "header = \"a b\"\nequals = \"a\"",
+            "header = \"a\"",
+            "header = \"a\"\nequals = \"a\"\nexists = true",
+            "header = \"a\"\nregex = \"(\"",
+        ] {
+            let config = toml::from_str::<Filter>(config).unwrap();
+            assert!(RequestFilter::new(&config).is_err());
+        }
+    }
+}
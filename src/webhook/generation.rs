@@ -0,0 +1,259 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    error::Error,
+    hash::Hash,
+    sync::{Arc, RwLock},
+};
+
+use hyper::Method;
+
+use crate::{
+    config::{Aggregation, Endpoint, Execution, Protocol, Server, Trigger},
+    err::DemeanorError,
+    plugins::NewPlugin,
+    webhook::{dedup::DedupCache, filter::RequestFilter, router::Router, verify::Verifier},
+};
+
+fn validate_server(server: &Server, triggers: &HashSet<Trigger>) -> Result<(), DemeanorError> {
+    if server.protocols.is_empty() {
+        return Err(DemeanorError::new("At least one protocol must be enabled"));
+    }
+    if let Some(p) = server
+        .protocols
+        .iter()
+        .find(|p| matches!(p, Protocol::Unknown(_)))
+    {
+        return Err(DemeanorError::new(format!("Protocol {} not recognized", p)));
+    }
+
+    for endpoint in server.endpoints.iter() {
+        if endpoint.require_client_cert && server.tls_client_ca_path.is_none() {
+            return Err(DemeanorError::new(format!(
+                "Endpoint {} requires a client certificate but tls_client_ca_path is not set",
+                endpoint.path
+            )));
+        }
+        if let Some(m) = endpoint
+            .methods
+            .iter()
+            .flatten()
+            .find(|m| Method::from_bytes(m.to_ascii_uppercase().as_bytes()).is_err())
+        {
+            return Err(DemeanorError::new(format!(
+                "Endpoint {} has invalid method {}",
+                endpoint.path, m
+            )));
+        }
+        if endpoint.triggers().next().is_none() {
+            return Err(DemeanorError::new(format!(
+                "Endpoint {} has no trigger_name or trigger_names",
+                endpoint.path
+            )));
+        }
+        if let Some(name) = endpoint.triggers().find(|name| !triggers.contains(*name)) {
+            return Err(DemeanorError::new(format!(
+                "Endpoint {} refers to unknown trigger {}",
+                endpoint.path, name
+            )));
+        }
+        if let Execution::Unknown(ref e) = endpoint.execution {
+            return Err(DemeanorError::new(format!(
+                "Endpoint {} has unrecognized execution {}",
+                endpoint.path, e
+            )));
+        }
+        if let Aggregation::Unknown(ref a) = endpoint.aggregation {
+            return Err(DemeanorError::new(format!(
+                "Endpoint {} has unrecognized aggregation {}",
+                endpoint.path, a
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Per-endpoint state that outlives the config structs: signature verifiers,
+/// compiled filters and dedup caches.
+pub(crate) struct EndpointState {
+    pub verifier: Option<Verifier>,
+    pub filters: Vec<RequestFilter>,
+    pub dedup: Option<Arc<DedupCache>>,
+}
+
+/// Build the endpoint state of a server. Dedup caches whose settings did not
+/// change are carried over from the previous generation so a reload does not
+/// forget deliveries already seen.
+fn load_endpoints(
+    server: &Server,
+    previous: Option<(&Server, &HashMap<String, EndpointState>)>,
+) -> Result<HashMap<String, EndpointState>, DemeanorError> {
+    let mut states = HashMap::new();
+    for endpoint in server.endpoints.iter() {
+        let verifier = endpoint
+            .verify
+            .as_ref()
+            .map(Verifier::new)
+            .transpose()
+            .map_err(|e| DemeanorError::new(format!("Endpoint {}: {}", endpoint.path, e)))?;
+        let filters = endpoint
+            .filters
+            .iter()
+            .map(RequestFilter::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| DemeanorError::new(format!("Endpoint {}: {}", endpoint.path, e)))?;
+        let reused = previous.and_then(|(server, states)| {
+            let prev = server.endpoints.get(&endpoint.path)?;
+            if prev.dedup.is_some() && prev.dedup == endpoint.dedup {
+                states.get(&endpoint.path)?.dedup.clone()
+            } else {
+                None
+            }
+        });
+        let dedup =
+            match (reused, endpoint.dedup.as_ref()) {
+                (Some(d), _) => Some(d),
+                (None, Some(config)) => Some(Arc::new(DedupCache::new(config).map_err(|e| {
+                    DemeanorError::new(format!("Endpoint {}: {}", endpoint.path, e))
+                })?)),
+                (None, None) => None,
+            };
+        states.insert(
+            endpoint.path.clone(),
+            EndpointState {
+                verifier,
+                filters,
+                dedup,
+            },
+        );
+    }
+    Ok(states)
+}
+
+fn persist_path(endpoint: &Endpoint) -> Option<&String> {
+    endpoint.dedup.as_ref()?.persist_path.as_ref()
+}
+
+/// Reject dedup caches that would write the same file: two endpoints sharing
+/// a `persist_path`, or a new cache taking over the file of a cache from the
+/// previous generation, which keeps writing it while its requests drain.
+fn check_persist_paths<P>(
+    servers: &[Server],
+    previous: Option<&Generation<P>>,
+) -> Result<(), DemeanorError> {
+    let mut paths = HashMap::new();
+    for (index, server) in servers.iter().enumerate() {
+        for endpoint in server.endpoints.iter() {
+            let path = match persist_path(endpoint) {
+                Some(p) => p,
+                None => continue,
+            };
+            if let Some(other) = paths.insert(path, &endpoint.path) {
+                return Err(DemeanorError::new(format!(
+                    "Endpoints {} and {} both persist their dedup cache to {}",
+                    other, endpoint.path, path
+                )));
+            }
+            let previous = match previous {
+                Some(p) => p,
+                None => continue,
+            };
+            let reused = previous
+                .servers
+                .get(index)
+                .and_then(|s| s.endpoints.get(&endpoint.path))
+                .is_some_and(|prev| prev.dedup == endpoint.dedup);
+            let in_use = previous
+                .servers
+                .iter()
+                .flat_map(|s| s.endpoints.iter())
+                .any(|e| persist_path(e) == Some(path));
+            if !reused && in_use {
+                return Err(DemeanorError::new(format!(
+                    "Endpoint {}: dedup persist_path {} is used by the running configuration; \
+                     changing its dedup settings requires a restart",
+                    endpoint.path, path
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Routing tables, endpoint state and trigger plugins loaded from one
+/// version of the config file. `servers`, `routers` and `endpoints` are
+/// indexed the same way as the running listeners.
+pub(crate) struct Generation<P> {
+    pub servers: Vec<Arc<Server>>,
+    pub routers: Vec<Arc<Router>>,
+    /// Endpoint state keyed by endpoint path.
+    pub endpoints: Vec<Arc<HashMap<String, EndpointState>>>,
+    pub triggers: Arc<HashSet<P>>,
+}
+
+impl<P> Generation<P>
+where
+    P: NewPlugin + Eq + Hash + Borrow<String>,
+{
+    /// Validate the servers and load every trigger plugin, failing without
+    /// side effects on the running generation if any of them is invalid.
+    /// `previous` is the generation being replaced on reload.
+    pub fn load(
+        servers: Vec<Server>,
+        mut triggers: HashSet<Trigger>,
+        previous: Option<&Generation<P>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        check_persist_paths(&servers, previous)?;
+        let mut routers = Vec::new();
+        let mut endpoints = Vec::new();
+        for (index, server) in servers.iter().enumerate() {
+            let previous = previous.map(|g| (&*g.servers[index], &*g.endpoints[index]));
+            let (router, states) = validate_server(server, &triggers)
+                .and_then(|_| {
+                    Ok((
+                        Router::new(&server.endpoints)?,
+                        load_endpoints(server, previous)?,
+                    ))
+                })
+                .map_err(|e| DemeanorError::new(format!("{}: {}", server.listen_addr, e)))?;
+            routers.push(Arc::new(router));
+            endpoints.push(Arc::new(states));
+        }
+
+        let mut trigger_plugins = HashSet::new();
+        for trigger in triggers.drain() {
+            trigger_plugins.insert(P::new(trigger)?);
+        }
+
+        Ok(Generation {
+            servers: servers.into_iter().map(Arc::new).collect(),
+            routers,
+            endpoints,
+            triggers: Arc::new(trigger_plugins),
+        })
+    }
+}
+
+/// The currently active generation. Requests take a snapshot when they start
+/// so a reload never affects requests already in flight.
+pub(crate) struct SharedGeneration<P>(RwLock<Arc<Generation<P>>>);
+
+impl<P> SharedGeneration<P> {
+    pub fn new(generation: Generation<P>) -> Self {
+        SharedGeneration(RwLock::new(Arc::new(generation)))
+    }
+
+    pub fn load(&self) -> Arc<Generation<P>> {
+        match self.0.read() {
+            Ok(g) => Arc::clone(&g),
+            Err(e) => Arc::clone(&e.into_inner()),
+        }
+    }
+
+    pub fn store(&self, generation: Generation<P>) {
+        match self.0.write() {
+            Ok(mut g) => *g = Arc::new(generation),
+            Err(e) => *e.into_inner() = Arc::new(generation),
+        }
+    }
+}
@@ -4,6 +4,8 @@ use async_trait::async_trait;
 use futures::stream::Stream;
 use tokio::io::{self, AsyncRead, AsyncWrite};
 
+use crate::config::Server;
+
 #[async_trait]
 pub(crate) trait Listener<C, E>: Sized
 where
@@ -11,5 +13,5 @@ where
     C: AsyncRead + AsyncWrite,
     E: Error,
 {
-    async fn bind(listen_addr: &str) -> Result<Self, E>;
+    async fn bind(server: &Server) -> Result<Self, E>;
 }
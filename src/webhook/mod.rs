@@ -1,80 +1,95 @@
+mod dedup;
+mod fanout;
+mod filter;
+mod generation;
 mod listener;
+mod response;
+mod router;
+mod systemd;
 mod tcp;
+mod tls;
 mod unix;
+mod verify;
+
+pub use self::systemd::inherit_listen_fds;
+pub use self::tls::{TlsIdentity, UseTls};
 
 use std::{
     borrow::Borrow,
     collections::{HashMap, HashSet},
     convert::Infallible,
     error::Error,
-    ffi::CString,
-    fmt::Debug,
+    ffi::{CString, NulError},
+    fmt::{Debug, Display},
     future::Future,
     hash::Hash,
     io,
     marker::Unpin,
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
-use futures::StreamExt;
+use futures::{future, StreamExt};
 use http_body_util::{BodyExt, Full};
 use hyper::{
     body::{Bytes, Incoming},
-    server::conn::http2::Builder,
+    header,
     service::Service,
-    Request, Response,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder, graceful::GracefulShutdown, graceful::Watcher},
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::{TcpStream, UnixStream},
+    signal::unix::{signal, SignalKind},
 };
-use tokio_native_tls::TlsAcceptor;
-use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use tokio_stream::wrappers::TcpListenerStream;
 
-use missdemeanor::CRequest;
+use missdemeanor::{Body, CRequest, CResponse};
 
+#[cfg(feature = "rustls")]
+use crate::webhook::tls::client_identity;
 use crate::{
-    config::{self, Server, TomlConfig},
+    config::{self, Protocol, Server, TomlConfig},
     err::DemeanorError,
-    plugins::{NewPlugin, Plugin, PluginError},
-    webhook::listener::Listener,
+    plugins::{NewPlugin, Plugin, PluginError, PluginErrorKind},
+    webhook::{
+        dedup::Delivery,
+        fanout::run_triggers,
+        filter::matches_all,
+        generation::{EndpointState, Generation, SharedGeneration},
+        listener::Listener,
+        response::build_response,
+        router::{parse_query, Router},
+        systemd::{SystemdListenerStream, SystemdStream},
+        tls::{negotiated_protocols, ClientIdentity, TlsAcceptor},
+        unix::UnixSocketListener,
+    },
 };
 
-pub enum UseTls {
-    Yes(TlsIdentity),
-    No,
-}
-
-impl UseTls {
-    pub fn use_tls(&self) -> bool {
-        matches!(self, UseTls::Yes(_))
-    }
-}
-
-#[derive(Clone)]
-pub struct TlsIdentity {
-    identity: Vec<u8>,
-    pw: String,
+fn to_cstring_pairs(pairs: Vec<(String, String)>) -> Result<Vec<(CString, CString)>, NulError> {
+    pairs
+        .into_iter()
+        .map(|(k, v)| Ok((CString::new(k)?, CString::new(v)?)))
+        .collect()
 }
 
-impl TlsIdentity {
-    pub fn new(identity: Vec<u8>, pw: String) -> Self {
-        TlsIdentity { identity, pw }
-    }
-
-    pub fn to_identity(&self) -> Result<native_tls::Identity, native_tls::Error> {
-        native_tls::Identity::from_pkcs12(&self.identity, &self.pw)
-    }
-}
-
-async fn service<P>(
-    req: Request<Incoming>,
+async fn service<P, B>(
+    req: Request<B>,
     server_box: Arc<Server>,
+    router: Arc<Router>,
+    endpoint_states: Arc<HashMap<String, EndpointState>>,
     trigger_plugins_box: Arc<HashSet<P>>,
+    client: Option<Arc<ClientIdentity>>,
 ) -> Result<Response<Full<Bytes>>, PluginError>
 where
-    P: Hash + Eq + Borrow<String> + Plugin,
+    P: 'static + Hash + Eq + Borrow<String> + Plugin + Send + Sync,
+    B: hyper::body::Body,
+    B::Error: Display,
 {
     let (parts, body) = req.into_parts();
     let method = CString::new(parts.method.as_str()).map_err(|e| {
@@ -82,21 +97,97 @@ where
         PluginError::new(400, "Invalid method")
     })?;
 
-    let uri = parts.uri.to_string();
-    let name = server_box
-        .endpoints
-        .get(&uri)
-        .map(|e| &e.trigger_name)
-        .ok_or_else(|| {
-            error!("Failed to find endpoint");
-            PluginError::new(404, "Endpoint not found")
+    let path = parts.uri.path();
+    let (endpoint_path, path_params) = router.route(path).ok_or_else(|| {
+        error!("Failed to find endpoint for {}", path);
+        PluginError::new(404, "Endpoint not found")
+    })?;
+    let endpoint = server_box.endpoints.get(endpoint_path).ok_or_else(|| {
+        error!("Failed to find endpoint");
+        PluginError::new(404, "Endpoint not found")
+    })?;
+    if endpoint.require_client_cert && client.is_none() {
+        error!("Endpoint {} requires a client certificate", endpoint.path);
+        return Err(PluginError::new(403, "Client certificate required"));
+    }
+    if let Some(allow) = endpoint.allow() {
+        if parts.method == Method::OPTIONS && !endpoint.allows_method(Method::OPTIONS.as_str()) {
+            return Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(header::ALLOW, allow)
+                .body(Full::new(Bytes::new()))
+                .unwrap_or_default());
+        }
+        if !endpoint.allows_method(parts.method.as_str()) {
+            error!(
+                "Method {} not allowed for endpoint {}",
+                parts.method, endpoint.path
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, allow)
+                .body(Full::new(Bytes::from("Method not allowed")))
+                .unwrap_or_default());
+        }
+    }
+
+    let body = match body.collect().await {
+        Ok(b) => b.to_bytes().to_vec(),
+        Err(e) => {
+            warn!("{e}");
+            return Err(PluginError::new(400, "Failed to receive body"));
+        }
+    };
+    let state = endpoint_states.get(endpoint_path);
+    if let Some(verifier) = state.and_then(|s| s.verifier.as_ref()) {
+        verifier.verify(&parts.headers, &body).map_err(|e| {
+            error!("Rejecting request to {}: {}", endpoint.path, e);
+            PluginError::new(401, "Webhook verification failed")
         })?;
-    let uri_cstring = CString::new(uri).map_err(|e| {
+    }
+    if let Some(s) = state {
+        if !matches_all(&s.filters, &parts.headers, &body) {
+            info!(
+                "Request to {} did not match its filters; ignoring",
+                endpoint.path
+            );
+            return Ok(Response::new(Full::new(Bytes::from("Ignored"))));
+        }
+    }
+    let dedup = state
+        .and_then(|s| s.dedup.as_ref())
+        .map(|d| (d, d.key(&parts.headers, &body)));
+
+    let query = parts.uri.query().unwrap_or("");
+    let uri_cstring = CString::new(
+        parts
+            .uri
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .unwrap_or(path),
+    )
+    .map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Invalid path")
+    })?;
+    let path_cstring = CString::new(path).map_err(|e| {
         error!("{}", e);
         PluginError::new(400, "Invalid path")
     })?;
+    let query_cstring = CString::new(query).map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Invalid query")
+    })?;
+    let path_params = to_cstring_pairs(path_params).map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Invalid path parameter")
+    })?;
+    let query_params = to_cstring_pairs(parse_query(query)).map_err(|e| {
+        error!("{}", e);
+        PluginError::new(400, "Invalid query parameter")
+    })?;
 
-    let mut headers = HashMap::new();
+    let mut headers = Vec::new();
     for (header, value) in &parts.headers {
         let header_cstring = CString::new(header.to_string()).map_err(|e| {
             error!("{}", e);
@@ -110,43 +201,181 @@ where
             error!("{}", e);
             PluginError::new(400, "Invalid header value")
         })?;
-        headers.insert(header_cstring, val_cstring);
+        headers.push((header_cstring, val_cstring));
     }
 
-    let body_cstring = CString::new(match body.collect().await {
-        Ok(b) => b.to_bytes().to_vec(),
-        Err(e) => {
-            warn!("{e}");
-            return Err(PluginError::new(400, "Failed to receive body"));
+    let (client_subject, client_sans) = match client {
+        Some(ref c) => {
+            let subject = CString::new(c.subject.as_str()).map_err(|e| {
+                error!("{}", e);
+                PluginError::new(400, "Invalid client certificate subject")
+            })?;
+            let sans = c
+                .sans
+                .iter()
+                .map(|san| CString::new(san.as_str()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| {
+                    error!("{}", e);
+                    PluginError::new(400, "Invalid client certificate SAN")
+                })?;
+            (Some(subject), sans)
         }
-    })
-    .map_err(|e| {
-        error!("{}", e);
-        PluginError::new(400, "Invalid body")
-    })?;
+        None => (None, Vec::new()),
+    };
 
-    let crequest = CRequest {
+    let crequest = Arc::new(CRequest {
         method,
         uri: uri_cstring,
+        path: path_cstring,
+        query: query_cstring,
+        path_params,
+        query_params,
         headers,
-        body: body_cstring,
+        body: Body::new(body),
+        client_subject,
+        client_sans,
+    });
+
+    let in_flight = match dedup {
+        Some((cache, ref key)) => match cache.begin(key) {
+            Delivery::New(in_flight) => Some(in_flight),
+            Delivery::Duplicate => {
+                info!("Ignoring duplicate delivery {} to {}", key, endpoint.path);
+                return Ok(Response::builder()
+                    .status(cache.status())
+                    .body(Full::new(Bytes::from("Duplicate delivery")))
+                    .unwrap_or_default());
+            }
+            // The first attempt may still fail, so ask the sender to retry
+            // rather than acknowledging the delivery.
+            Delivery::InProgress => {
+                info!(
+                    "Delivery {} to {} is already being processed",
+                    key, endpoint.path
+                );
+                return Err(PluginError::new(409, "Delivery in progress"));
+            }
+        },
+        None => None,
     };
 
-    let trigger = trigger_plugins_box.get(name).ok_or_else(|| {
-        error!("Trigger plugin {} not found", name);
-        PluginError::new(500, "Plugin not found")
-    })?;
-    trigger.run_trigger(crequest).map_err(|e| {
-        error!("Trigger plugin failed with error: {}", e);
-        PluginError::new(500, "Trigger phase failed")
-    })?;
+    let result = match (&endpoint.trigger_name, endpoint.trigger_names.is_empty()) {
+        (Some(name), true) => match trigger_plugins_box.get(name) {
+            Some(trigger) => {
+                let mut response = CResponse::new();
+                match trigger.run_trigger(crequest, &mut response).await {
+                    Ok(()) => build_response(response, StatusCode::OK, "Success!"),
+                    Err(e) if e.kind() != PluginErrorKind::Failure => Err(e),
+                    Err(e) => {
+                        error!("Trigger plugin failed with error: {}", e);
+                        build_response(
+                            response,
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Trigger phase failed",
+                        )
+                    }
+                }
+            }
+            None => {
+                error!("Trigger plugin {} not found", name);
+                Err(PluginError::new(500, "Plugin not found"))
+            }
+        },
+        (_, _) => Ok(run_triggers(endpoint, trigger_plugins_box, crequest).await),
+    };
+    // Only remember deliveries whose triggers succeeded, so a redelivery
+    // retries a failed one.
+    if let Some(in_flight) = in_flight {
+        if matches!(result, Ok(ref r) if r.status().is_success()) {
+            in_flight.commit();
+        }
+    }
+    result
+}
 
-    Ok(Response::new(Full::new(Bytes::from("Success!"))))
+async fn serve_connection<I, P>(
+    io: I,
+    protocols: Vec<Protocol>,
+    service: WebookService<P>,
+    watcher: Watcher,
+) where
+    I: 'static + AsyncRead + AsyncWrite + Send + Unpin,
+    P: 'static + NewPlugin + Plugin + Eq + Hash + Borrow<String> + Send + Sync,
+{
+    let mut builder = Builder::new(TokioExecutor::new());
+    let http1 = protocols.contains(&Protocol::Http1);
+    let http2 = protocols.contains(&Protocol::Http2);
+    if http1 && !http2 {
+        builder = builder.http1_only();
+    } else if http2 && !http1 {
+        builder = builder.http2_only();
+    }
+    let conn = builder.serve_connection(TokioIo::new(io), service);
+    if let Err(e) = watcher.watch(conn).await {
+        error!("{}", e);
+    }
+}
+
+/// Complete the TLS handshake if the server uses TLS and serve HTTP on the
+/// resulting stream.
+async fn accept_connection<C, P>(
+    sock: C,
+    protocols: Vec<Protocol>,
+    service: WebookService<P>,
+    tls_acceptor: Arc<Option<TlsAcceptor>>,
+    watcher: Watcher,
+) where
+    C: 'static + AsyncRead + AsyncWrite + Debug + Send + Unpin,
+    P: 'static + NewPlugin + Plugin + Eq + Hash + Borrow<String> + Send + Sync,
+{
+    match *tls_acceptor {
+        Some(TlsAcceptor::NativeTls(ref acceptor)) => {
+            let tls_stream = match acceptor.accept(sock).await {
+                Ok(ts) => ts,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let alpn = match tls_stream.get_ref().negotiated_alpn() {
+                Ok(alpn) => alpn,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let protocols = negotiated_protocols(alpn.as_deref(), &protocols);
+            serve_connection(tls_stream, protocols, service, watcher).await;
+        }
+        #[cfg(feature = "rustls")]
+        Some(TlsAcceptor::Rustls(ref acceptor)) => {
+            let tls_stream = match acceptor.accept(sock).await {
+                Ok(ts) => ts,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+            let protocols =
+                negotiated_protocols(tls_stream.get_ref().1.alpn_protocol(), &protocols);
+            let service = WebookService {
+                client: client_identity(tls_stream.get_ref().1.peer_certificates()).map(Arc::new),
+                ..service
+            };
+            serve_connection(tls_stream, protocols, service, watcher).await;
+        }
+        None => {
+            serve_connection(sock, protocols, service, watcher).await;
+        }
+    }
 }
 
 struct WebookService<P> {
-    server: Arc<Server>,
-    plugins: Arc<HashSet<P>>,
+    generation: Arc<SharedGeneration<P>>,
+    /// Index of the listener this connection was accepted on.
+    index: usize,
+    client: Option<Arc<ClientIdentity>>,
 }
 
 impl<P> Service<Request<Incoming>> for WebookService<P>
@@ -158,10 +387,14 @@ where
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let server = Arc::clone(&self.server);
-        let plugins = Arc::clone(&self.plugins);
+        let generation = self.generation.load();
+        let server = Arc::clone(&generation.servers[self.index]);
+        let router = Arc::clone(&generation.routers[self.index]);
+        let endpoint_states = Arc::clone(&generation.endpoints[self.index]);
+        let plugins = Arc::clone(&generation.triggers);
+        let client = self.client.clone();
         Box::pin(async {
-            match service(req, server, plugins).await {
+            match service(req, server, router, endpoint_states, plugins, client).await {
                 Ok(resp) => Ok(resp),
                 Err(e) => Ok(e.into_response()),
             }
@@ -169,114 +402,199 @@ where
     }
 }
 
+async fn shutdown_signal() -> Result<(), io::Error> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM"),
+        _ = sigint.recv() => info!("Received SIGINT"),
+    }
+    Ok(())
+}
+
 pub struct WebhookServer<P> {
     identity: Option<TlsIdentity>,
-    server: Arc<Server>,
-    triggers: Arc<HashSet<P>>,
+    shutdown_grace_period: Duration,
+    config_path: String,
+    /// Listener settings as loaded at startup; only the routing tables and
+    /// triggers are replaced on reload.
+    servers: Vec<Arc<Server>>,
+    generation: Arc<SharedGeneration<P>>,
 }
 
 impl<P> WebhookServer<P>
 where
     P: 'static + NewPlugin + Plugin + Eq + Hash + Borrow<String> + Send + Sync,
 {
-    pub fn new(use_tls: UseTls, mut toml_config: TomlConfig) -> Result<Self, Box<dyn Error>> {
+    pub fn new(
+        use_tls: UseTls,
+        config_path: String,
+        toml_config: TomlConfig,
+    ) -> Result<Self, Box<dyn Error>> {
         let identity = match use_tls {
             UseTls::Yes(identity) => Some(identity),
             UseTls::No => None,
         };
 
-        let mut trigger_plugins_hs = HashSet::new();
-        for trigger in toml_config.triggers.drain() {
-            trigger_plugins_hs.insert(P::new(trigger)?);
-        }
-
-        let trigger_plugins = Arc::new(trigger_plugins_hs);
+        let generation = Generation::load(toml_config.servers, toml_config.triggers, None)?;
 
         Ok(WebhookServer {
             identity,
-            server: Arc::new(toml_config.server),
-            triggers: trigger_plugins,
+            shutdown_grace_period: Duration::from_secs(toml_config.shutdown_grace_period),
+            config_path,
+            servers: generation.servers.clone(),
+            generation: Arc::new(SharedGeneration::new(generation)),
         })
     }
 
-    async fn listen<L, C, E>(self) -> Result<(), Box<dyn Error>>
+    /// Re-read the config file and swap in its routing tables and triggers.
+    /// The running generation is kept if anything fails to validate or load.
+    /// Loading plugins may block, so this runs on the blocking thread pool.
+    fn reload(
+        config_path: &str,
+        running_servers: &[Arc<Server>],
+        shutdown_grace_period: Duration,
+        shared: &SharedGeneration<P>,
+    ) -> Result<(), Box<dyn Error>> {
+        let toml_config = config::parse_config(config_path.to_string())?;
+        if toml_config.shutdown_grace_period != shutdown_grace_period.as_secs() {
+            return Err(Box::new(DemeanorError::new(
+                "Changing shutdown_grace_period requires a restart",
+            )));
+        }
+        let mut new_servers = toml_config.servers;
+        let mut servers = Vec::new();
+        for running in running_servers.iter() {
+            let pos = new_servers
+                .iter()
+                .position(|s| s.listen_addr == running.listen_addr)
+                .ok_or_else(|| {
+                    DemeanorError::new(format!(
+                        "Removing listener {} requires a restart",
+                        running.listen_addr
+                    ))
+                })?;
+            let server = new_servers.swap_remove(pos);
+            if let Some(setting) = server.changed_listener_setting(running) {
+                return Err(Box::new(DemeanorError::new(format!(
+                    "Changing {} of listener {} requires a restart",
+                    setting, running.listen_addr
+                ))));
+            }
+            servers.push(server);
+        }
+        if let Some(s) = new_servers.first() {
+            return Err(Box::new(DemeanorError::new(format!(
+                "Adding listener {} requires a restart",
+                s.listen_addr
+            ))));
+        }
+
+        let previous = shared.load();
+        let generation = Generation::load(servers, toml_config.triggers, Some(&previous))?;
+        shared.store(generation);
+        Ok(())
+    }
+
+    async fn reload_on_sighup(&self) -> Result<(), io::Error> {
+        let mut sighup = signal(SignalKind::hangup())?;
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP; reloading {}", self.config_path);
+            let config_path = self.config_path.clone();
+            let servers = self.servers.clone();
+            let shutdown_grace_period = self.shutdown_grace_period;
+            let generation = Arc::clone(&self.generation);
+            let reloaded = tokio::task::spawn_blocking(move || {
+                Self::reload(&config_path, &servers, shutdown_grace_period, &generation)
+                    .map_err(|e| e.to_string())
+            })
+            .await;
+            match reloaded {
+                Ok(Ok(())) => info!("Configuration reloaded"),
+                Ok(Err(e)) => error!("Keeping running configuration; reload failed: {}", e),
+                Err(e) => error!("Keeping running configuration; reload panicked: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn listen<L, C, E>(
+        &self,
+        index: usize,
+        server: Arc<Server>,
+        graceful: &GracefulShutdown,
+    ) -> Result<(), Box<dyn Error>>
     where
-        L: 'static + Listener<C, E> + Send,
+        L: 'static + Listener<C, E> + Send + Unpin,
         C: 'static + AsyncRead + AsyncWrite + Debug + Send + Unpin,
         E: 'static + Error + Send,
     {
-        let mut tls_acceptor = None;
-        if let Some(ident) = self.identity.as_ref() {
-            let acceptor = native_tls::TlsAcceptor::new(ident.to_identity()?)?;
-            tls_acceptor = Some(TlsAcceptor::from(acceptor));
-        }
+        let tls_acceptor = Arc::new(if server.use_tls {
+            Some(TlsAcceptor::new(self.identity.as_ref(), &server)?)
+        } else {
+            None
+        });
 
-        let listener = L::bind(&self.server.listen_addr).await?;
-
-        let server_for_each = Arc::clone(&self.server);
-        let trigger_plugins_for_each = Arc::clone(&self.triggers);
-        let tls_acceptor_for_each = Arc::new(tls_acceptor);
-
-        listener
-            .for_each(move |sock_result| {
-                let server_serve = Arc::clone(&server_for_each);
-                let trigger_plugins_serve = Arc::clone(&trigger_plugins_for_each);
-                let tls_acceptor_inner = Arc::clone(&tls_acceptor_for_each);
-
-                async move {
-                    let sock = match sock_result {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("{}", e);
-                            return;
-                        }
-                    };
-
-                    if let Some(ref acceptor) = *tls_acceptor_inner {
-                        let tls_stream = match acceptor.accept(sock).await {
-                            Ok(ts) => ts,
-                            Err(e) => {
-                                error!("{}", e);
-                                return;
-                            }
-                        };
-                        let _ = tokio::spawn(
-                            Builder::new(hyper_util::rt::TokioExecutor::new()).serve_connection(
-                                hyper_util::rt::TokioIo::new(tls_stream),
-                                WebookService {
-                                    plugins: Arc::clone(&trigger_plugins_serve),
-                                    server: Arc::clone(&server_serve),
-                                },
-                            ),
-                        );
-                    } else {
-                        let _ = tokio::spawn(
-                            Builder::new(hyper_util::rt::TokioExecutor::new()).serve_connection(
-                                hyper_util::rt::TokioIo::new(sock),
-                                WebookService {
-                                    plugins: Arc::clone(&trigger_plugins_serve),
-                                    server: Arc::clone(&server_serve),
-                                },
-                            ),
-                        );
-                    }
+        let mut listener = L::bind(&server).await?;
+        info!("Listening on {}", server.listen_addr);
+
+        while let Some(sock_result) = listener.next().await {
+            let sock = match sock_result {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("{}", e);
+                    continue;
                 }
-            })
-            .await;
+            };
+
+            let service = WebookService {
+                generation: Arc::clone(&self.generation),
+                index,
+                client: None,
+            };
+            tokio::spawn(accept_connection(
+                sock,
+                server.protocols.clone(),
+                service,
+                Arc::clone(&tls_acceptor),
+                graceful.watcher(),
+            ));
+        }
         Ok(())
     }
 
-    pub async fn serve(self) -> Result<(), Box<dyn Error>> {
-        match self.server.server_type {
+    async fn serve_server(
+        &self,
+        index: usize,
+        server: Arc<Server>,
+        graceful: &GracefulShutdown,
+    ) -> Result<(), Box<dyn Error>> {
+        match server.server_type {
             config::ServerType::Webhook => {
-                self.listen::<TcpListenerStream, TcpStream, io::Error>()
-                    .await?
+                self.listen::<TcpListenerStream, TcpStream, io::Error>(
+                    index,
+                    Arc::clone(&server),
+                    graceful,
+                )
+                .await?
             }
             config::ServerType::UnixSocket => {
-                self.listen::<UnixListenerStream, UnixStream, io::Error>()
-                    .await?
+                self.listen::<UnixSocketListener, UnixStream, io::Error>(
+                    index,
+                    Arc::clone(&server),
+                    graceful,
+                )
+                .await?
+            }
+            config::ServerType::Systemd => {
+                self.listen::<SystemdListenerStream, SystemdStream, io::Error>(
+                    index,
+                    Arc::clone(&server),
+                    graceful,
+                )
+                .await?
             }
-            config::ServerType::UnknownServerType => {
+            config::ServerType::Unknown => {
                 return Err(Box::new(DemeanorError::new(
                     "Server type not recognized - exiting",
                 )));
@@ -284,4 +602,165 @@ where
         };
         Ok(())
     }
+
+    /// Serve all configured listeners, reloading on SIGHUP, until SIGTERM or
+    /// SIGINT is received, then stop accepting connections and wait for
+    /// in-flight requests to finish. Returns an error if they did not finish
+    /// within the grace period.
+    pub async fn serve(self) -> Result<(), Box<dyn Error>> {
+        let graceful = GracefulShutdown::new();
+        let listeners = future::try_join_all(
+            self.servers
+                .iter()
+                .enumerate()
+                .map(|(index, server)| self.serve_server(index, Arc::clone(server), &graceful)),
+        );
+        tokio::select! {
+            res = listeners => {
+                res?;
+            }
+            res = shutdown_signal() => {
+                res?;
+            }
+            res = self.reload_on_sighup() => {
+                res?;
+            }
+        }
+
+        info!(
+            "Stopped accepting connections; waiting up to {} seconds for in-flight requests",
+            self.shutdown_grace_period.as_secs()
+        );
+        match tokio::time::timeout(self.shutdown_grace_period, graceful.shutdown()).await {
+            Ok(()) => {
+                info!("All in-flight requests finished");
+                Ok(())
+            }
+            Err(_) => Err(Box::new(DemeanorError::new(
+                "Grace period expired before all in-flight requests finished",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        env,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use async_trait::async_trait;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use crate::config::Trigger;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Counts the requests that reach it.
+    struct Recorder(Trigger);
+
+    impl NewPlugin for Recorder {
+        fn new(trigger: Trigger) -> Result<Self, io::Error> {
+            Ok(Recorder(trigger))
+        }
+    }
+
+    #[async_trait]
+    impl Plugin for Recorder {
+        async fn run_trigger(
+            &self,
+            _: Arc<CRequest>,
+            _: &mut CResponse,
+        ) -> Result<(), PluginError> {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    impl Hash for Recorder {
+        fn hash<H>(&self, hasher: &mut H)
+        where
+            H: std::hash::Hasher,
+        {
+            self.0.hash(hasher)
+        }
+    }
+
+    impl PartialEq for Recorder {
+        fn eq(&self, rhs: &Self) -> bool {
+            self.0 == rhs.0
+        }
+    }
+
+    impl Eq for Recorder {}
+
+    impl Borrow<String> for Recorder {
+        fn borrow(&self) -> &String {
+            self.0.borrow()
+        }
+    }
+
+    async fn send(generation: &Generation<Recorder>, signature: &str) -> StatusCode {
+        let req = Request::post("/hook")
+            .header("X-Hub-Signature-256", signature)
+            .body(Full::new(Bytes::from("payload")))
+            .unwrap();
+        match service(
+            req,
+            Arc::clone(&generation.servers[0]),
+            Arc::clone(&generation.routers[0]),
+            Arc::clone(&generation.endpoints[0]),
+            Arc::clone(&generation.triggers),
+            None,
+        )
+        .await
+        {
+            Ok(r) => r.status(),
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn verification_runs_before_plugins() {
+        env::set_var("MISS_DEMEANOR_TEST_VERIFY_SECRET", "secret");
+        let server = toml::from_str::<Server>(
+            r#"
+            server_type = "webhook"
+            listen_addr = "127.0.0.1:0"
+            use_tls = false
+
+            [[endpoints]]
+            path = "/hook"
+            trigger_name = "recorder"
+            verify = { provider = "github", secret_env = "MISS_DEMEANOR_TEST_VERIFY_SECRET" }
+            "#,
+        )
+        .unwrap();
+        let trigger = toml::from_str::<Trigger>(
+            r#"
+            name = "recorder"
+            plugin_path = "unused"
+            "#,
+        )
+        .unwrap();
+        let mut triggers = HashSet::new();
+        triggers.insert(trigger);
+        let generation = Generation::<Recorder>::load(vec![server], triggers, None).unwrap();
+
+        assert_eq!(
+            send(&generation, "sha256=00").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(b"payload");
+        let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        assert_eq!(send(&generation, &signature).await, StatusCode::OK);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
 }
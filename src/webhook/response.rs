@@ -0,0 +1,43 @@
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue},
+    Response, StatusCode,
+};
+
+use missdemeanor::CResponse;
+
+use crate::plugins::PluginError;
+
+/// Turn the response a plugin filled in into an HTTP response, using
+/// `status` and `body` for whatever the plugin left unset.
+pub(crate) fn build_response(
+    plugin_response: CResponse,
+    status: StatusCode,
+    body: &'static str,
+) -> Result<Response<Full<Bytes>>, PluginError> {
+    let status = match plugin_response.status {
+        Some(s) => StatusCode::from_u16(s).map_err(|e| {
+            error!("Plugin set invalid status {}: {}", s, e);
+            PluginError::new(500, "Invalid plugin response")
+        })?,
+        None => status,
+    };
+    let mut response = Response::new(Full::new(match plugin_response.body {
+        Some(b) => Bytes::from(b),
+        None => Bytes::from(body),
+    }));
+    *response.status_mut() = status;
+    for (name, value) in plugin_response.headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| {
+            error!("Plugin set invalid header name {}: {}", name, e);
+            PluginError::new(500, "Invalid plugin response")
+        })?;
+        let value = HeaderValue::from_str(&value).map_err(|e| {
+            error!("Plugin set invalid value for header {}: {}", name, e);
+            PluginError::new(500, "Invalid plugin response")
+        })?;
+        response.headers_mut().append(name, value);
+    }
+    Ok(response)
+}
@@ -0,0 +1,287 @@
+use std::{cmp::Reverse, collections::HashSet};
+
+use crate::{config::Endpoint, err::DemeanorError};
+
+/// Decode `%XX` escapes, and `+` as a space in query strings. Invalid escapes
+/// are kept as they are.
+pub(crate) fn percent_decode(s: &str, plus_as_space: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                Some(b) => {
+                    decoded.push(b);
+                    i += 3;
+                    continue;
+                }
+                None => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Split a query string into decoded key-value pairs in the order they
+/// appear.
+pub(crate) fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    path: String,
+    segments: Vec<Segment>,
+    prefix: bool,
+}
+
+impl Route {
+    fn new(endpoint: &Endpoint) -> Result<Self, DemeanorError> {
+        if !endpoint.path.starts_with('/') {
+            return Err(DemeanorError::new(format!(
+                "Endpoint path {} must start with /",
+                endpoint.path
+            )));
+        }
+        let mut segments = endpoint.path[1..]
+            .split('/')
+            .map(|s| {
+                if let Some(name) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    if name.is_empty() || name.contains(['{', '}']) {
+                        return Err(DemeanorError::new(format!(
+                            "Invalid path parameter in endpoint {}",
+                            endpoint.path
+                        )));
+                    }
+                    Ok(Segment::Param(name.to_string()))
+                } else if s.contains(['{', '}']) {
+                    Err(DemeanorError::new(format!(
+                        "Path parameters must span a whole segment in endpoint {}",
+                        endpoint.path
+                    )))
+                } else {
+                    Ok(Segment::Literal(s.to_string()))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // A trailing slash on a prefix only marks the segment boundary.
+        if endpoint.prefix && matches!(segments.last(), Some(Segment::Literal(s)) if s.is_empty()) {
+            segments.pop();
+        }
+        Ok(Route {
+            path: endpoint.path.clone(),
+            segments,
+            prefix: endpoint.prefix,
+        })
+    }
+
+    fn params(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| matches!(s, Segment::Param(_)))
+            .count()
+    }
+
+    fn matches(&self, segments: &[&str]) -> Option<Vec<(String, String)>> {
+        if segments.len() < self.segments.len()
+            || (!self.prefix && segments.len() != self.segments.len())
+        {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (pattern, segment) in self.segments.iter().zip(segments) {
+            match pattern {
+                Segment::Literal(l) if l == segment => (),
+                Segment::Param(name) if !segment.is_empty() => {
+                    params.push((name.clone(), percent_decode(segment, false)));
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Endpoint lookup supporting exact paths, `{param}` templates and
+/// segment-wise prefixes. Exact paths take precedence over templates, which
+/// take precedence over prefixes; the longest matching prefix wins.
+pub(crate) struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new(endpoints: &HashSet<Endpoint>) -> Result<Self, DemeanorError> {
+        let mut routes = endpoints
+            .iter()
+            .map(Route::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        routes.sort_by_key(|r| {
+            (
+                r.prefix,
+                Reverse(if r.prefix { r.segments.len() } else { 0 }),
+                r.params(),
+                r.path.clone(),
+            )
+        });
+        Ok(Router { routes })
+    }
+
+    /// Find the endpoint for a request path, returning its configured path and
+    /// the decoded path parameters.
+    pub fn route(&self, path: &str) -> Option<(&String, Vec<(String, String)>)> {
+        let segments = path
+            .strip_prefix('/')
+            .unwrap_or(path)
+            .split('/')
+            .collect::<Vec<_>>();
+        self.routes
+            .iter()
+            .find_map(|r| r.matches(&segments).map(|p| (&r.path, p)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(routes: &[(&str, bool)]) -> Router {
+        let endpoints = routes
+            .iter()
+            .map(|(path, prefix)| {
+                toml::from_str::<Endpoint>(&format!(
+                    "path = \"{}\"\ntrigger_name = \"t\"\nprefix = {}",
+                    path, prefix
+                ))
+                .unwrap()
+            })
+            .collect();
+        Router::new(&endpoints).unwrap()
+    }
+
+    fn route<'a>(router: &'a Router, path: &str) -> Option<&'a str> {
+        router.route(path).map(|(p, _)| p.as_str())
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn exact_beats_template() {
+        let router = router(&[
+            ("/repos/{owner}/{repo}", false),
+            ("/repos/special/x", false),
+        ]);
+        assert_eq!(route(&router, "/repos/special/x"), Some("/repos/special/x"));
+        assert_eq!(
+            router.route("/repos/special/y"),
+            Some((
+                &"/repos/{owner}/{repo}".to_string(),
+                pairs(&[("owner", "special"), ("repo", "y")])
+            ))
+        );
+        assert_eq!(route(&router, "/repos/special"), None);
+        assert_eq!(route(&router, "/repos//x"), None);
+    }
+
+    #[test]
+    fn template_beats_prefix() {
+        let router = router(&[("/repos", true), ("/repos/{owner}/{repo}", false)]);
+        assert_eq!(route(&router, "/repos/a/b"), Some("/repos/{owner}/{repo}"));
+        assert_eq!(route(&router, "/repos/a/b/c"), Some("/repos"));
+        assert_eq!(route(&router, "/repos/a"), Some("/repos"));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let router = router(&[("/hooks", true), ("/hooks/github", true), ("/", true)]);
+        assert_eq!(route(&router, "/hooks/github/push"), Some("/hooks/github"));
+        assert_eq!(route(&router, "/hooks/github"), Some("/hooks/github"));
+        assert_eq!(route(&router, "/hooks/gitlab"), Some("/hooks"));
+        assert_eq!(route(&router, "/other"), Some("/"));
+    }
+
+    #[test]
+    fn prefix_matches_whole_segments() {
+        let router = router(&[("/hooks", true)]);
+        assert_eq!(route(&router, "/hooks"), Some("/hooks"));
+        assert_eq!(route(&router, "/hooks/"), Some("/hooks"));
+        assert_eq!(route(&router, "/hooks/a/b"), Some("/hooks"));
+        assert_eq!(route(&router, "/hooksx"), None);
+    }
+
+    #[test]
+    fn prefix_trailing_slash() {
+        let router = router(&[("/hooks/", true)]);
+        assert_eq!(route(&router, "/hooks"), Some("/hooks/"));
+        assert_eq!(route(&router, "/hooks/"), Some("/hooks/"));
+        assert_eq!(route(&router, "/hooks/a"), Some("/hooks/"));
+        assert_eq!(route(&router, "/hooksx"), None);
+
+        // Without prefix the slash is part of the exact path.
+        let router = super::tests::router(&[("/hooks/", false)]);
+        assert_eq!(route(&router, "/hooks/"), Some("/hooks/"));
+        assert_eq!(route(&router, "/hooks"), None);
+    }
+
+    #[test]
+    fn path_params_are_decoded() {
+        let router = router(&[("/repos/{owner}", false)]);
+        assert_eq!(
+            router.route("/repos/a%20b+c").map(|(_, p)| p),
+            Some(pairs(&[("owner", "a b+c")]))
+        );
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("%41%2f%2F", false), "A//");
+        assert_eq!(percent_decode("a+b", false), "a+b");
+        assert_eq!(percent_decode("a+b", true), "a b");
+        assert_eq!(percent_decode("%2B", true), "+");
+        assert_eq!(percent_decode("%zz%4", false), "%zz%4");
+        assert_eq!(percent_decode("100%", false), "100%");
+        assert_eq!(percent_decode("%C3%A9", false), "é");
+    }
+
+    #[test]
+    fn query_parsing() {
+        assert_eq!(
+            parse_query("a=1&b=x+y&&c&d=%3D&a=2"),
+            pairs(&[("a", "1"), ("b", "x y"), ("c", ""), ("d", "="), ("a", "2")])
+        );
+        assert_eq!(parse_query(""), Vec::new());
+        assert_eq!(parse_query("k%20ey=v=w"), pairs(&[("k ey", "v=w")]));
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for path in ["repos", "/repos/{}", "/repos/x{owner}", "/repos/{a{b}}"] {
+            let endpoint =
+                toml::from_str::<Endpoint>(&format!("path = \"{}\"\ntrigger_name = \"t\"", path))
+                    .unwrap();
+            assert!(Route::new(&endpoint).is_err(), "{} was accepted", path);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    env, io, mem,
+    os::unix::io::{FromRawFd, RawFd},
+    pin::Pin,
+    process,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use futures::stream::Stream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+
+use crate::{config::Server, webhook::listener::Listener};
+
+/// First file descriptor passed by systemd as defined by `sd_listen_fds(3)`.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Descriptors passed by systemd with their names, or why there are none.
+/// `None` until `inherit_listen_fds` has run.
+type ListenFds = Result<Vec<(RawFd, String)>, &'static str>;
+
+static LISTEN_FDS: Mutex<Option<ListenFds>> = Mutex::new(None);
+
+/// File descriptors already handed to a listener so that two servers selecting
+/// the same name never take ownership of the same socket.
+static CLAIMED_FDS: Mutex<Vec<RawFd>> = Mutex::new(Vec::new());
+
+fn read_listen_fds() -> ListenFds {
+    let pid = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok())
+        .ok_or("LISTEN_PID is not set - not started by systemd socket activation")?;
+    if pid != process::id() {
+        return Err("LISTEN_PID does not match this process");
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .ok_or("LISTEN_FDS is not set")?;
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd names descriptors "unknown" when no name was configured
+            let name = names.next().filter(|n| !n.is_empty()).unwrap_or("unknown");
+            (fd, name.to_string())
+        })
+        .collect())
+}
+
+/// Take the sockets passed by systemd out of the environment. Every inherited
+/// descriptor is marked close-on-exec, whether or not a server uses it, and
+/// the `LISTEN_*` variables are removed, so that neither reaches plugin
+/// processes. Must run before any other thread or process is started.
+pub fn inherit_listen_fds() -> Result<(), io::Error> {
+    let mut listen_fds = LISTEN_FDS
+        .lock()
+        .map_err(|_| io::Error::other("Socket activation state poisoned"))?;
+    if listen_fds.is_some() {
+        return Ok(());
+    }
+    let fds = read_listen_fds();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if let Ok(ref fds) = fds {
+        for &(fd, _) in fds.iter() {
+            set_cloexec(fd)?;
+        }
+    }
+    *listen_fds = Some(fds);
+    Ok(())
+}
+
+/// Find the first unclaimed file descriptor passed by systemd whose name in
+/// `LISTEN_FDNAMES` matches `name`.
+fn claim_fd(name: &str) -> Result<RawFd, io::Error> {
+    inherit_listen_fds()?;
+    let listen_fds = LISTEN_FDS
+        .lock()
+        .map_err(|_| io::Error::other("Socket activation state poisoned"))?;
+    let fds = match *listen_fds {
+        Some(Ok(ref fds)) => fds,
+        Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::NotFound, e)),
+        None => return Err(io::Error::other("Socket activation state missing")),
+    };
+
+    let mut claimed = CLAIMED_FDS
+        .lock()
+        .map_err(|_| io::Error::other("Socket activation state poisoned"))?;
+    for &(fd, ref fd_name) in fds.iter() {
+        if fd_name == name && !claimed.contains(&fd) {
+            claimed.push(fd);
+            return Ok(fd);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No socket named {} passed by systemd", name),
+    ))
+}
+
+fn socket_family(fd: RawFd) -> Result<libc::sa_family_t, io::Error> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr.ss_family)
+}
+
+/// Inherited sockets do not have `FD_CLOEXEC` set and would otherwise leak
+/// into plugin processes.
+fn set_cloexec(fd: RawFd) -> Result<(), io::Error> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub(crate) enum SystemdStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for SystemdStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SystemdStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            SystemdStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SystemdStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SystemdStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            SystemdStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SystemdStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            SystemdStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SystemdStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            SystemdStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Listener for a TCP or unix socket inherited through systemd socket
+/// activation and selected by its `FileDescriptorName=`.
+pub(crate) enum SystemdListenerStream {
+    Tcp(TcpListenerStream),
+    Unix(UnixListenerStream),
+}
+
+impl Stream for SystemdListenerStream {
+    type Item = io::Result<SystemdStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            SystemdListenerStream::Tcp(l) => Pin::new(l)
+                .poll_next(cx)
+                .map(|o| o.map(|r| r.map(SystemdStream::Tcp))),
+            SystemdListenerStream::Unix(l) => Pin::new(l)
+                .poll_next(cx)
+                .map(|o| o.map(|r| r.map(SystemdStream::Unix))),
+        }
+    }
+}
+
+#[async_trait]
+impl Listener<SystemdStream, io::Error> for SystemdListenerStream {
+    async fn bind(server: &Server) -> Result<Self, io::Error> {
+        let listen_addr = server.listen_addr.as_str();
+        let fd = claim_fd(listen_addr)?;
+        match i32::from(socket_family(fd)?) {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Ok(SystemdListenerStream::Tcp(TcpListenerStream::new(
+                    TcpListener::from_std(listener)?,
+                )))
+            }
+            libc::AF_UNIX => {
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                Ok(SystemdListenerStream::Unix(UnixListenerStream::new(
+                    UnixListener::from_std(listener)?,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported socket family {} for {}", family, listen_addr),
+            )),
+        }
+    }
+}
@@ -4,12 +4,13 @@ use async_trait::async_trait;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::wrappers::TcpListenerStream;
 
-use crate::webhook::listener::Listener;
+use crate::{config::Server, webhook::listener::Listener};
 
 #[async_trait]
 impl Listener<TcpStream, io::Error> for TcpListenerStream {
-    async fn bind(listen_addr: &str) -> Result<Self, io::Error> {
-        let sock_addr = listen_addr
+    async fn bind(server: &Server) -> Result<Self, io::Error> {
+        let sock_addr = server
+            .listen_addr
             .to_socket_addrs()?
             .next()
             .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))?;
@@ -0,0 +1,233 @@
+use std::error::Error;
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+
+use crate::{
+    config::{Protocol, Server, TlsBackend},
+    err::DemeanorError,
+};
+
+pub enum UseTls {
+    Yes(TlsIdentity),
+    No,
+}
+
+impl UseTls {
+    pub fn use_tls(&self) -> bool {
+        matches!(self, UseTls::Yes(_))
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsIdentity {
+    identity: Vec<u8>,
+    pw: String,
+}
+
+impl TlsIdentity {
+    pub fn new(identity: Vec<u8>, pw: String) -> Self {
+        TlsIdentity { identity, pw }
+    }
+
+    pub fn to_identity(&self) -> Result<native_tls::Identity, native_tls::Error> {
+        native_tls::Identity::from_pkcs12(&self.identity, &self.pw)
+    }
+}
+
+/// ALPN identifiers for the configured protocols in order of server preference.
+fn alpn_protocols(protocols: &[Protocol]) -> Vec<&'static str> {
+    let mut alpn = Vec::new();
+    if protocols.contains(&Protocol::Http2) {
+        alpn.push("h2");
+    }
+    if protocols.contains(&Protocol::Http1) {
+        alpn.push("http/1.1");
+    }
+    alpn
+}
+
+/// Narrow the protocols served on a connection to the one selected by ALPN,
+/// falling back to the configured set if the client did not negotiate one.
+pub(crate) fn negotiated_protocols(alpn: Option<&[u8]>, configured: &[Protocol]) -> Vec<Protocol> {
+    match alpn {
+        Some(b"h2") => vec![Protocol::Http2],
+        Some(b"http/1.1") => vec![Protocol::Http1],
+        _ => configured.to_vec(),
+    }
+}
+
+/// Identity of a client that presented a certificate verified against the
+/// configured CA bundle.
+pub(crate) struct ClientIdentity {
+    pub subject: String,
+    pub sans: Vec<String>,
+}
+
+#[cfg(feature = "rustls")]
+fn format_general_name(name: &x509_parser::extensions::GeneralName) -> Option<String> {
+    use std::{
+        convert::TryFrom,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    };
+    use x509_parser::extensions::GeneralName;
+
+    match *name {
+        GeneralName::DNSName(s) => Some(format!("DNS:{}", s)),
+        GeneralName::RFC822Name(s) => Some(format!("email:{}", s)),
+        GeneralName::URI(s) => Some(format!("URI:{}", s)),
+        GeneralName::IPAddress(b) => {
+            let addr = match b.len() {
+                4 => IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(b).ok()?)),
+                16 => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(b).ok()?)),
+                _ => return None,
+            };
+            Some(format!("IP:{}", addr))
+        }
+        GeneralName::DirectoryName(ref n) => Some(format!("DirName:{}", n)),
+        _ => None,
+    }
+}
+
+/// Extract the subject DN and SANs from the leaf certificate of a verified
+/// client chain.
+#[cfg(feature = "rustls")]
+pub(crate) fn client_identity(
+    certs: Option<&[rustls::pki_types::CertificateDer<'_>]>,
+) -> Option<ClientIdentity> {
+    let leaf = certs?.first()?;
+    let cert = match x509_parser::parse_x509_certificate(leaf) {
+        Ok((_, c)) => c,
+        Err(e) => {
+            error!("Failed to parse client certificate: {}", e);
+            return None;
+        }
+    };
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => ext
+            .value
+            .general_names
+            .iter()
+            .filter_map(format_general_name)
+            .collect(),
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            warn!("Failed to parse client certificate SANs: {}", e);
+            Vec::new()
+        }
+    };
+    Some(ClientIdentity {
+        subject: cert.subject().to_string(),
+        sans,
+    })
+}
+
+pub(crate) enum TlsAcceptor {
+    NativeTls(tokio_native_tls::TlsAcceptor),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::TlsAcceptor),
+}
+
+impl TlsAcceptor {
+    pub fn new(identity: Option<&TlsIdentity>, server: &Server) -> Result<Self, Box<dyn Error>> {
+        match server.tls_backend {
+            TlsBackend::NativeTls => {
+                if server.tls_client_ca_path.is_some() {
+                    return Err(Box::new(DemeanorError::new(
+                        "Client certificate verification requires the rustls backend",
+                    )));
+                }
+                let ident = identity.ok_or_else(|| {
+                    DemeanorError::new("native-tls backend requires a PKCS12 identity")
+                })?;
+                let acceptor = native_tls::TlsAcceptor::builder(ident.to_identity()?)
+                    .accept_alpn(&alpn_protocols(&server.protocols))
+                    .build()?;
+                Ok(TlsAcceptor::NativeTls(tokio_native_tls::TlsAcceptor::from(
+                    acceptor,
+                )))
+            }
+            TlsBackend::Rustls => Self::rustls(server),
+            TlsBackend::Unknown(ref s) => Err(Box::new(DemeanorError::new(format!(
+                "TLS backend {} not recognized",
+                s
+            )))),
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls(server: &Server) -> Result<Self, Box<dyn Error>> {
+        use rustls::{
+            crypto::ring,
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+            server::WebPkiClientVerifier,
+            RootCertStore, ServerConfig,
+        };
+
+        let (cert_path, key_path) = match (&server.tls_cert_path, &server.tls_key_path) {
+            (Some(c), Some(k)) => (c, k),
+            (_, _) => {
+                return Err(Box::new(DemeanorError::new(
+                    "rustls backend requires tls_cert_path and tls_key_path",
+                )));
+            }
+        };
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                DemeanorError::new(format!(
+                    "Failed to load certificates from {}: {}",
+                    cert_path, e
+                ))
+            })?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| {
+            DemeanorError::new(format!(
+                "Failed to load private key from {}: {}",
+                key_path, e
+            ))
+        })?;
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?;
+        // Client certificates are optional at the TLS layer so that endpoints
+        // without require_client_cert stay reachable; enforcement happens per
+        // endpoint once the request is routed.
+        let builder = match server.tls_client_ca_path {
+            Some(ref ca_path) => {
+                let mut roots = RootCertStore::empty();
+                let ca_certs = CertificateDer::pem_file_iter(ca_path)
+                    .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+                    .map_err(|e| {
+                        DemeanorError::new(format!(
+                            "Failed to load client CA bundle from {}: {}",
+                            ca_path, e
+                        ))
+                    })?;
+                for cert in ca_certs {
+                    roots.add(cert)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .allow_unauthenticated()
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = alpn_protocols(&server.protocols)
+            .into_iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect();
+        Ok(TlsAcceptor::Rustls(tokio_rustls::TlsAcceptor::from(
+            Arc::new(config),
+        )))
+    }
+
+    #[cfg(not(feature = "rustls"))]
+    fn rustls(_: &Server) -> Result<Self, Box<dyn Error>> {
+        Err(Box::new(DemeanorError::new(
+            "miss-demeanor was built without the rustls feature",
+        )))
+    }
+}
@@ -1,14 +1,234 @@
-use std::io;
+use std::{
+    ffi::CString,
+    fs::{self, DirBuilder, Permissions},
+    io, mem,
+    os::unix::{
+        fs::{self as unix_fs, DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+        net,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    process, ptr,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
 
 use async_trait::async_trait;
+use futures::stream::Stream;
 use tokio::net::{UnixListener, UnixStream};
 use tokio_stream::wrappers::UnixListenerStream;
 
-use crate::webhook::listener::Listener;
+use crate::{config::Server, webhook::listener::Listener};
+
+/// Unix socket listener that owns its socket file and unlinks it when dropped.
+pub(crate) struct UnixSocketListener {
+    inner: UnixListenerStream,
+    /// Path and inode of the socket file; `None` for abstract sockets.
+    socket_file: Option<(PathBuf, u64)>,
+}
+
+impl Stream for UnixSocketListener {
+    type Item = io::Result<UnixStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().inner).poll_next(cx)
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Some((ref path, ino)) = self.socket_file {
+            // Only remove the file if it is still the socket this listener
+            // created and has not been replaced by another instance.
+            match fs::symlink_metadata(path) {
+                Ok(m) if m.ino() == ino => {
+                    if let Err(e) = fs::remove_file(path) {
+                        warn!("Failed to remove socket {}: {}", path.display(), e);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Remove a socket file left behind by a previous instance. The file is only
+/// removed if it is a socket and nothing accepts connections on it.
+fn remove_stale_socket(path: &Path) -> Result<(), io::Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        )),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            info!("Removing stale socket {}", path.display());
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+static BINDS: AtomicU64 = AtomicU64::new(0);
+
+/// Bind a socket at `path` with its mode and owner already applied. The
+/// socket is created in a private directory next to `path` and only linked
+/// into place afterwards, so it is never reachable with the permissions the
+/// umask gives a new socket.
+fn bind_restricted(
+    path: &Path,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<UnixListener, io::Error> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let dir = parent.join(format!(
+        ".miss-demeanor-{}-{}",
+        process::id(),
+        BINDS.fetch_add(1, Ordering::Relaxed)
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp = dir.join("s");
+    let result = (|| {
+        let listener = UnixListener::bind(&tmp)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&tmp, Permissions::from_mode(mode))?;
+        }
+        if uid.is_some() || gid.is_some() {
+            unix_fs::chown(&tmp, uid, gid)?;
+        }
+        // Unlike a rename, linking fails rather than replacing a socket
+        // another instance bound in the meantime.
+        fs::hard_link(&tmp, path).map_err(|e| {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                )
+            } else {
+                e
+            }
+        })?;
+        Ok(listener)
+    })();
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+fn lookup_uid(owner: &str) -> Result<u32, io::Error> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(owner)?;
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+    let rc = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No user named {}", owner),
+        ));
+    }
+    Ok(pwd.pw_uid)
+}
+
+fn lookup_gid(group: &str) -> Result<u32, io::Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+    let rc = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No group named {}", group),
+        ));
+    }
+    Ok(grp.gr_gid)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> Result<net::UnixListener, io::Error> {
+    use std::os::linux::net::SocketAddrExt;
+
+    net::UnixListener::bind_addr(&net::SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_: &str) -> Result<net::UnixListener, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Abstract unix sockets are only supported on Linux",
+    ))
+}
 
 #[async_trait]
-impl Listener<UnixStream, io::Error> for UnixListenerStream {
-    async fn bind(listen_addr: &str) -> Result<Self, io::Error> {
-        Ok(UnixListenerStream::new(UnixListener::bind(listen_addr)?))
+impl Listener<UnixStream, io::Error> for UnixSocketListener {
+    async fn bind(server: &Server) -> Result<Self, io::Error> {
+        if let Some(name) = server.listen_addr.strip_prefix('@') {
+            if server.socket_mode.is_some()
+                || server.socket_owner.is_some()
+                || server.socket_group.is_some()
+            {
+                warn!("Socket permissions do not apply to abstract sockets; ignoring");
+            }
+            let listener = bind_abstract(name)?;
+            listener.set_nonblocking(true)?;
+            return Ok(UnixSocketListener {
+                inner: UnixListenerStream::new(UnixListener::from_std(listener)?),
+                socket_file: None,
+            });
+        }
+
+        let path = Path::new(&server.listen_addr);
+        let uid = server.socket_owner.as_deref().map(lookup_uid).transpose()?;
+        let gid = server.socket_group.as_deref().map(lookup_gid).transpose()?;
+        remove_stale_socket(path)?;
+        let listener = bind_restricted(path, server.socket_mode, uid, gid)?;
+        let ino = fs::symlink_metadata(path)?.ino();
+        Ok(UnixSocketListener {
+            inner: UnixListenerStream::new(listener),
+            socket_file: Some((path.to_path_buf(), ino)),
+        })
     }
 }
@@ -0,0 +1,484 @@
+use std::{
+    env, fs,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use hyper::HeaderMap;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::{Provider, Verification},
+    err::DemeanorError,
+};
+
+fn read_secret_file(path: &str) -> Result<String, DemeanorError> {
+    Ok(fs::read_to_string(path)
+        .map_err(|e| DemeanorError::new(format!("Failed to read secret {}: {}", path, e)))?
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+fn read_secret_env(var: &str) -> Result<String, DemeanorError> {
+    env::var(var)
+        .map_err(|e| DemeanorError::new(format!("Failed to read secret from ${}: {}", var, e)))
+}
+
+/// Load every configured secret. More than one secret is accepted while keys
+/// are being rotated.
+fn load_secrets(config: &Verification) -> Result<Vec<Vec<u8>>, DemeanorError> {
+    let mut secrets = Vec::new();
+    for path in config.secret_file.iter().chain(config.secret_files.iter()) {
+        secrets.push(read_secret_file(path)?);
+    }
+    for var in config.secret_env.iter().chain(config.secret_envs.iter()) {
+        secrets.push(read_secret_env(var)?);
+    }
+    if secrets.is_empty() {
+        return Err(DemeanorError::new(
+            "At least one of secret_file, secret_files, secret_env or secret_envs must be set",
+        ));
+    }
+
+    secrets
+        .into_iter()
+        .map(|secret| {
+            if secret.is_empty() {
+                return Err(DemeanorError::new("Webhook secret is empty"));
+            }
+            match (&config.provider, secret.strip_prefix("whsec_")) {
+                // Standard Webhooks secrets are base64 encoded behind a prefix.
+                (Provider::StandardWebhooks, Some(encoded)) => STANDARD
+                    .decode(encoded)
+                    .map_err(|e| DemeanorError::new(format!("Invalid webhook secret: {}", e))),
+                (_, _) => Ok(secret.into_bytes()),
+            }
+        })
+        .collect()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, &'static str> {
+    headers
+        .get(name)
+        .ok_or("Missing signature header")?
+        .to_str()
+        .map_err(|_| "Invalid signature header")
+}
+
+/// Reject timestamps, in seconds since the epoch, further than `tolerance`
+/// from the current time in either direction.
+fn check_timestamp(timestamp: &str, tolerance: Duration) -> Result<(), &'static str> {
+    let timestamp = Duration::from_secs(timestamp.trim().parse().map_err(|_| "Invalid timestamp")?);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "System clock is before the epoch")?;
+    if now.abs_diff(timestamp) > tolerance {
+        return Err("Timestamp outside tolerance");
+    }
+    Ok(())
+}
+
+/// Check an HMAC-SHA256 over the concatenation of `message` against each
+/// secret in constant time.
+fn verify_hmac_sha256(
+    secrets: &[Vec<u8>],
+    message: &[&[u8]],
+    signature: &[u8],
+) -> Result<(), &'static str> {
+    let matched = secrets.iter().any(|secret| {
+        let mut mac = match Hmac::<Sha256>::new_from_slice(secret) {
+            Ok(m) => m,
+            Err(_) => return false,
+        };
+        for part in message {
+            mac.update(part);
+        }
+        mac.verify_slice(signature).is_ok()
+    });
+    if matched {
+        Ok(())
+    } else {
+        Err("Signature mismatch")
+    }
+}
+
+fn decode_hex(signature: &str) -> Result<Vec<u8>, &'static str> {
+    hex::decode(signature.trim()).map_err(|_| "Malformed signature")
+}
+
+/// Authenticity check for webhook deliveries, run before any plugin sees the
+/// request.
+pub(crate) struct Verifier {
+    provider: Provider,
+    secrets: Vec<Vec<u8>>,
+    tolerance: Duration,
+}
+
+impl Verifier {
+    pub fn new(config: &Verification) -> Result<Self, DemeanorError> {
+        if let Provider::Unknown(ref p) = config.provider {
+            return Err(DemeanorError::new(format!(
+                "Verification provider {} not recognized",
+                p
+            )));
+        }
+        Ok(Verifier {
+            provider: config.provider.clone(),
+            secrets: load_secrets(config)?,
+            tolerance: Duration::from_secs(config.tolerance),
+        })
+    }
+
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), &'static str> {
+        match self.provider {
+            Provider::GitHub => {
+                let signature = header(headers, "x-hub-signature-256")?
+                    .strip_prefix("sha256=")
+                    .ok_or("Malformed signature")?;
+                verify_hmac_sha256(&self.secrets, &[body], &decode_hex(signature)?)
+            }
+            Provider::Gitea => {
+                let signature = match headers.get("x-gitea-signature") {
+                    Some(_) => header(headers, "x-gitea-signature")?,
+                    None => header(headers, "x-hub-signature-256")?
+                        .strip_prefix("sha256=")
+                        .ok_or("Malformed signature")?,
+                };
+                verify_hmac_sha256(&self.secrets, &[body], &decode_hex(signature)?)
+            }
+            Provider::GitLab => {
+                let token = header(headers, "x-gitlab-token")?;
+                let matched = self
+                    .secrets
+                    .iter()
+                    .any(|secret| bool::from(token.as_bytes().ct_eq(secret)));
+                if matched {
+                    Ok(())
+                } else {
+                    Err("Token mismatch")
+                }
+            }
+            Provider::Slack => {
+                let timestamp = header(headers, "x-slack-request-timestamp")?;
+                check_timestamp(timestamp, self.tolerance)?;
+                let signature = header(headers, "x-slack-signature")?
+                    .strip_prefix("v0=")
+                    .ok_or("Malformed signature")?;
+                verify_hmac_sha256(
+                    &self.secrets,
+                    &[b"v0:", timestamp.as_bytes(), b":", body],
+                    &decode_hex(signature)?,
+                )
+            }
+            Provider::StandardWebhooks => {
+                let id = header(headers, "webhook-id")?;
+                let timestamp = header(headers, "webhook-timestamp")?;
+                check_timestamp(timestamp, self.tolerance)?;
+                let message: &[&[u8]] = &[id.as_bytes(), b".", timestamp.as_bytes(), b".", body];
+                // The header holds a space separated list of versioned
+                // signatures; any valid v1 signature is accepted.
+                header(headers, "webhook-signature")?
+                    .split_whitespace()
+                    .filter_map(|s| s.strip_prefix("v1,"))
+                    .filter_map(|s| STANDARD.decode(s).ok())
+                    .find_map(|s| verify_hmac_sha256(&self.secrets, message, &s).ok())
+                    .ok_or("Signature mismatch")
+            }
+            Provider::Unknown(_) => Err("Verification provider not recognized"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::{HeaderName, HeaderValue};
+
+    // Example from GitHub's documentation on validating webhook deliveries.
+    const GITHUB_SECRET: &str = "It's a Secret to Everybody";
+    const GITHUB_BODY: &[u8] = b"Hello, World!";
+    const GITHUB_SIGNATURE: &str =
+        "757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    fn verifier(provider: Provider, secrets: &[&str]) -> Verifier {
+        Verifier {
+            provider,
+            secrets: secrets.iter().map(|s| s.as_bytes().to_vec()).collect(),
+            tolerance: Duration::from_secs(300),
+        }
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn github_signature() {
+        let signature = format!("sha256={}", GITHUB_SIGNATURE);
+        let headers = headers(&[("X-Hub-Signature-256", &signature)]);
+        assert!(verifier(Provider::GitHub, &[GITHUB_SECRET])
+            .verify(&headers, GITHUB_BODY)
+            .is_ok());
+        assert_eq!(
+            verifier(Provider::GitHub, &["wrong"]).verify(&headers, GITHUB_BODY),
+            Err("Signature mismatch")
+        );
+        assert_eq!(
+            verifier(Provider::GitHub, &[GITHUB_SECRET]).verify(&headers, b"Hello, World?"),
+            Err("Signature mismatch")
+        );
+    }
+
+    #[test]
+    fn gitea_signature() {
+        let gitea = headers(&[("X-Gitea-Signature", GITHUB_SIGNATURE)]);
+        assert!(verifier(Provider::Gitea, &[GITHUB_SECRET])
+            .verify(&gitea, GITHUB_BODY)
+            .is_ok());
+        assert_eq!(
+            verifier(Provider::Gitea, &["wrong"]).verify(&gitea, GITHUB_BODY),
+            Err("Signature mismatch")
+        );
+
+        // Gitea also sends the GitHub header, used when its own is absent.
+        let signature = format!("sha256={}", GITHUB_SIGNATURE);
+        let github = headers(&[("X-Hub-Signature-256", &signature)]);
+        assert!(verifier(Provider::Gitea, &[GITHUB_SECRET])
+            .verify(&github, GITHUB_BODY)
+            .is_ok());
+    }
+
+    #[test]
+    fn gitlab_token() {
+        let headers = headers(&[("X-Gitlab-Token", "token")]);
+        assert!(verifier(Provider::GitLab, &["token"])
+            .verify(&headers, b"")
+            .is_ok());
+        assert_eq!(
+            verifier(Provider::GitLab, &["other"]).verify(&headers, b""),
+            Err("Token mismatch")
+        );
+        assert_eq!(
+            verifier(Provider::GitLab, &["toke"]).verify(&headers, b""),
+            Err("Token mismatch")
+        );
+    }
+
+    #[test]
+    fn missing_header() {
+        let empty = HeaderMap::new();
+        for provider in [Provider::GitHub, Provider::Gitea, Provider::GitLab] {
+            assert_eq!(
+                verifier(provider, &[GITHUB_SECRET]).verify(&empty, GITHUB_BODY),
+                Err("Missing signature header")
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_header() {
+        let github = verifier(Provider::GitHub, &[GITHUB_SECRET]);
+        let no_prefix = headers(&[("X-Hub-Signature-256", GITHUB_SIGNATURE)]);
+        assert_eq!(
+            github.verify(&no_prefix, GITHUB_BODY),
+            Err("Malformed signature")
+        );
+        let not_hex = headers(&[("X-Hub-Signature-256", "sha256=not-hex")]);
+        assert_eq!(
+            github.verify(&not_hex, GITHUB_BODY),
+            Err("Malformed signature")
+        );
+        let truncated = format!("sha256={}", &GITHUB_SIGNATURE[..62]);
+        let truncated = headers(&[("X-Hub-Signature-256", &truncated)]);
+        assert_eq!(
+            github.verify(&truncated, GITHUB_BODY),
+            Err("Signature mismatch")
+        );
+
+        let mut opaque = HeaderMap::new();
+        opaque.insert(
+            "x-gitlab-token",
+            HeaderValue::from_bytes(b"\xfftoken").unwrap(),
+        );
+        assert_eq!(
+            verifier(Provider::GitLab, &["token"]).verify(&opaque, b""),
+            Err("Invalid signature header")
+        );
+    }
+
+    // Example from Slack's documentation on verifying requests.
+    const SLACK_SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const SLACK_TIMESTAMP: &str = "1531420618";
+    const SLACK_BODY: &[u8] = b"token=xyzz0WbapA4vBCDEFasx0q6G&team_id=T1DC2JH3J&team_domain=testteamnow&channel_id=G8PSS9T3V&channel_name=foobar&user_id=U2CERLKJA&user_name=roadrunner&command=%2Fwebhook-collect&text=&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2FT1DC2JH3J%2F397700885554%2F96rGlfmibIGlgcZRskXaIFfN&trigger_id=398738663015.47445629121.803a0bc887a14d10d2c447fce8b6703c";
+    const SLACK_SIGNATURE: &str =
+        "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+
+    // Example from the Standard Webhooks specification.
+    const SW_SECRET: &str = "whsec_MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw";
+    const SW_ID: &str = "msg_p5jXN8AQM9LWM0D4loKWxJek";
+    const SW_TIMESTAMP: &str = "1614265330";
+    const SW_BODY: &[u8] = br#"{"test": 2432232314}"#;
+    const SW_SIGNATURE: &str = "v1,g0hM9SsE+OTPJTGt/tmIKtSyZlE3uFJELVlNIOLJ1OE=";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// A tolerance that accepts the fixed timestamp of a published example.
+    fn accepting(timestamp: &str) -> Duration {
+        Duration::from_secs(now() - timestamp.parse::<u64>().unwrap() + 60)
+    }
+
+    /// A verifier loading its secrets from the environment like a
+    /// configured endpoint does.
+    fn configured(provider: &str, secrets: &[(&str, &str)], tolerance: Duration) -> Verifier {
+        for (var, secret) in secrets {
+            env::set_var(var, secret);
+        }
+        let config = toml::from_str::<Verification>(&format!(
+            "provider = \"{}\"\nsecret_envs = [{}]\ntolerance = {}",
+            provider,
+            secrets
+                .iter()
+                .map(|(var, _)| format!("\"{}\"", var))
+                .collect::<Vec<_>>()
+                .join(", "),
+            tolerance.as_secs()
+        ))
+        .unwrap();
+        Verifier::new(&config).unwrap()
+    }
+
+    #[test]
+    fn slack_signature() {
+        let headers = headers(&[
+            ("X-Slack-Request-Timestamp", SLACK_TIMESTAMP),
+            ("X-Slack-Signature", SLACK_SIGNATURE),
+        ]);
+        let mut slack = verifier(Provider::Slack, &[SLACK_SECRET]);
+        slack.tolerance = accepting(SLACK_TIMESTAMP);
+        assert!(slack.verify(&headers, SLACK_BODY).is_ok());
+        assert_eq!(
+            slack.verify(&headers, b"token=other"),
+            Err("Signature mismatch")
+        );
+
+        // The published example is years old.
+        slack.tolerance = Duration::from_secs(300);
+        assert_eq!(
+            slack.verify(&headers, SLACK_BODY),
+            Err("Timestamp outside tolerance")
+        );
+    }
+
+    #[test]
+    fn standard_webhooks_signature() {
+        let sw = configured(
+            "standard_webhooks",
+            &[("MISS_DEMEANOR_TEST_SW_SECRET", SW_SECRET)],
+            accepting(SW_TIMESTAMP),
+        );
+        // The secret is used base64 decoded, without its prefix.
+        assert_eq!(
+            sw.secrets,
+            vec![STANDARD.decode("MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw").unwrap()]
+        );
+
+        let headers = headers(&[
+            ("webhook-id", SW_ID),
+            ("webhook-timestamp", SW_TIMESTAMP),
+            ("webhook-signature", SW_SIGNATURE),
+        ]);
+        assert!(sw.verify(&headers, SW_BODY).is_ok());
+        assert_eq!(
+            sw.verify(&headers, br#"{"test": 1}"#),
+            Err("Signature mismatch")
+        );
+    }
+
+    #[test]
+    fn standard_webhooks_multiple_signatures() {
+        let sw = configured(
+            "standard_webhooks",
+            &[("MISS_DEMEANOR_TEST_SW_MULTI_SECRET", SW_SECRET)],
+            accepting(SW_TIMESTAMP),
+        );
+        let signatures = format!("v1,bm9wZQ== v2,{} {}", &SW_SIGNATURE[3..], SW_SIGNATURE);
+        let valid = headers(&[
+            ("webhook-id", SW_ID),
+            ("webhook-timestamp", SW_TIMESTAMP),
+            ("webhook-signature", &signatures),
+        ]);
+        assert!(sw.verify(&valid, SW_BODY).is_ok());
+
+        // A valid signature under an unsupported version does not count.
+        let signatures = format!("v1,bm9wZQ== v2,{}", &SW_SIGNATURE[3..]);
+        let invalid = headers(&[
+            ("webhook-id", SW_ID),
+            ("webhook-timestamp", SW_TIMESTAMP),
+            ("webhook-signature", &signatures),
+        ]);
+        assert_eq!(sw.verify(&invalid, SW_BODY), Err("Signature mismatch"));
+    }
+
+    #[test]
+    fn rotated_secrets() {
+        let slack = configured(
+            "slack",
+            &[
+                ("MISS_DEMEANOR_TEST_SLACK_NEW", "new-secret"),
+                ("MISS_DEMEANOR_TEST_SLACK_OLD", SLACK_SECRET),
+            ],
+            accepting(SLACK_TIMESTAMP),
+        );
+        let headers = headers(&[
+            ("X-Slack-Request-Timestamp", SLACK_TIMESTAMP),
+            ("X-Slack-Signature", SLACK_SIGNATURE),
+        ]);
+        assert!(slack.verify(&headers, SLACK_BODY).is_ok());
+
+        let retired = configured(
+            "slack",
+            &[("MISS_DEMEANOR_TEST_SLACK_RETIRED", "new-secret")],
+            accepting(SLACK_TIMESTAMP),
+        );
+        assert_eq!(
+            retired.verify(&headers, SLACK_BODY),
+            Err("Signature mismatch")
+        );
+    }
+
+    #[test]
+    fn timestamp_tolerance() {
+        let tolerance = Duration::from_secs(300);
+        let now = now();
+        // A couple of seconds of slack keep the checks stable if the clock
+        // ticks while the test runs.
+        for inside in [now - 298, now, now + 298] {
+            assert!(check_timestamp(&inside.to_string(), tolerance).is_ok());
+        }
+        for outside in [now - 302, now + 302] {
+            assert_eq!(
+                check_timestamp(&outside.to_string(), tolerance),
+                Err("Timestamp outside tolerance")
+            );
+        }
+        assert_eq!(
+            check_timestamp("yesterday", tolerance),
+            Err("Invalid timestamp")
+        );
+    }
+}